@@ -0,0 +1,116 @@
+use anyhow::bail;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use protocol::{bitcoincash, monero, persist::FileStore, protocol::Transition};
+
+#[derive(Debug, Deserialize)]
+pub struct Quote {
+    pub id: String,
+    #[serde(with = "bitcoincash::util::amount::serde::as_sat")]
+    pub bch_amount: bitcoincash::Amount,
+    #[serde(with = "monero::util::amount::serde::as_pico")]
+    pub xmr_amount: monero::Amount,
+}
+
+pub async fn get_quote(
+    client: &reqwest::Client,
+    base_url: &str,
+    path: &str,
+    bch_amount: bitcoincash::Amount,
+) -> anyhow::Result<Quote> {
+    let response = client
+        .get(format!("{base_url}/trader/quote"))
+        .query(&[
+            ("path", path),
+            ("bch_amount", &bch_amount.to_sat().to_string()),
+        ])
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => Ok(response.json::<Quote>().await?),
+        code => {
+            let body = response.text().await?;
+            bail!("[ERROR]: {code} - {body}");
+        }
+    }
+}
+
+pub async fn create_new_trade(
+    client: &reqwest::Client,
+    base_url: &str,
+    path: &str,
+    timelock1: u32,
+    timelock2: u32,
+    quote: &Quote,
+) -> anyhow::Result<String> {
+    let response = client
+        .post(format!("{base_url}/trader"))
+        .json(&json!({
+           "path": path,
+           "timelock1": timelock1,
+           "timelock2": timelock2,
+           "bch_amount": quote.bch_amount.to_sat(),
+           "xmr_amount": quote.xmr_amount.as_pico(),
+           "quote_id": quote.id
+        }))
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => {
+            let body = response.json::<serde_json::Value>().await?;
+            Ok(body["trade_id"].as_str().unwrap().to_string())
+        }
+        code => {
+            let body = response.text().await?;
+            bail!("[ERROR]: {code} - {body}");
+        }
+    }
+}
+
+pub async fn get_server_transition(
+    client: &reqwest::Client,
+    base_url: &str,
+    trade_id: &str,
+) -> anyhow::Result<Option<Transition>> {
+    let response = client
+        .get(format!("{base_url}/trader/{trade_id}"))
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => Ok(response.json::<Option<Transition>>().await?),
+        code => {
+            let body = response.text().await?;
+            bail!("[ERROR]: {code} - {body}");
+        }
+    }
+}
+
+pub async fn send_transition(
+    client: &reqwest::Client,
+    base_url: &str,
+    trade_id: &str,
+    transition: &Transition,
+) -> anyhow::Result<()> {
+    let response = client
+        .patch(format!("{base_url}/trader/{trade_id}"))
+        .json(transition)
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => Ok(()),
+        code => {
+            let body = response.text().await?;
+            bail!("[ERROR] {code} - {body}");
+        }
+    }
+}
+
+pub fn trade_store() -> FileStore {
+    FileStore::new("./.trades", "-client.json")
+}
@@ -0,0 +1,338 @@
+use std::{sync::Arc, time::Duration};
+
+use protocol::{
+    bitcoincash::{self},
+    blockchain::{self, watch::ScriptWatcher},
+    bob,
+    keys::{bitcoin::random_private_key, KeyPrivate},
+    lifecycle::Lifecycle,
+    monero_rpc,
+    persist::{self, Config, SwapStore, VERSION},
+    protocol::{Swap, DEFAULT_MINING_FEE},
+    protocol::{SwapEvents, SwapWrapper},
+    wallet::BchWallet,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex},
+    time::sleep,
+};
+
+use api::{create_new_trade, get_quote, get_server_transition, send_transition, trade_store};
+
+mod api;
+mod config;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config = config::load()?;
+    let node = config.node;
+    if let Some(passphrase) = &node.trade_passphrase {
+        persist::set_passphrase(passphrase);
+    }
+    let server_url = config.server_url.as_str();
+    let bch_min_confirmation = node.bch_min_conf;
+    let bch_mempool = node.bch_mempool;
+    let monero_network = node.monero_network;
+    let bch_network = node.bch_network;
+    let electrum_servers = node.electrum_servers()?;
+
+    // Bought xmr are swept here
+    let xmr_recv = node.xmr_recv;
+
+    // Fund the swaplock from this wallet, or manually when not set
+    let bch_wallet = node.bch_wallet_wif.map(|wif| {
+        Arc::new(BchWallet::new(
+            bitcoincash::PrivateKey::from_wif(&wif).unwrap(),
+            bch_network,
+        ))
+    });
+
+    // ===================================================
+
+    let req_client = reqwest::Client::new();
+    let bch_server = Arc::new(
+        blockchain::TcpElectrum::connect(
+            electrum_servers,
+            Duration::from_secs(node.electrum_timeout_secs),
+        )
+        .await?,
+    );
+
+    let monerod = Arc::new(
+        monero_rpc::RpcClientBuilder::new()
+            .build(node.monerod_addr)?
+            .daemon(),
+    );
+    let monero_wallet = Arc::new(Mutex::new(
+        monero_rpc::RpcClientBuilder::new()
+            .build(node.monero_wallet_addr)?
+            .wallet(),
+    ));
+
+    println!("Subscribing for new block");
+    let tip = bch_server.headers_subscribe().await?;
+    println!("Current height: {}", tip.height);
+    let watcher = Arc::new(ScriptWatcher::new(bch_network));
+    watcher.sync_tip(&bch_server, &tip).await?;
+    println!("========================================");
+
+    println!("Generating new keys...");
+    let refund_privkey = random_private_key(bch_network);
+    let secp = bitcoincash::secp256k1::Secp256k1::signing_only();
+    let refund_pub = refund_privkey.public_key(&secp);
+    let refund_addr = refund_pub.pubkey_hash();
+    let refund_script = bitcoincash::Script::new_p2pkh(&refund_addr);
+
+    let timelock1 = node.timelock1;
+    let timelock2 = node.timelock2;
+    let min_mining_fee = node.min_mining_fee;
+    let max_mining_fee = node.max_mining_fee;
+
+    println!("Requesting quote...");
+    let quote = get_quote(
+        &req_client,
+        server_url,
+        "bch->xmr",
+        bitcoincash::Amount::from_sat(config.bch_amount_sats),
+    )
+    .await?;
+    println!("Quote: {:?}", quote);
+    let bch_amount = quote.bch_amount;
+    let xmr_amount = quote.xmr_amount;
+
+    // Propose what the network currently asks, within our limits
+    let mining_fee = blockchain::estimate_mining_fee(&bch_server, 1)
+        .await
+        .unwrap_or(DEFAULT_MINING_FEE)
+        .clamp(min_mining_fee, max_mining_fee);
+
+    let swap = bob::Bob::new(Swap {
+        id: "".to_owned(),
+        keys: KeyPrivate::random(bch_network),
+
+        bch_amount,
+        xmr_amount,
+
+        xmr_network: monero_network,
+        bch_network,
+
+        bch_recv: refund_script,
+        xmr_recv,
+
+        timelock1,
+        timelock2,
+
+        mining_fee,
+        min_mining_fee,
+        max_mining_fee,
+    });
+
+    let string_json = serde_json::to_string_pretty(&swap.swap.keys).unwrap();
+    println!("Private Keys: {string_json}");
+    println!("Bch refund private key: {}", refund_privkey);
+
+    let swap = SwapWrapper::Bob(swap);
+
+    println!("========================================");
+
+    println!("Creating new trade...");
+    let trade_id = create_new_trade(
+        &req_client,
+        server_url,
+        "bch->xmr",
+        timelock1,
+        timelock2,
+        &quote,
+    )
+    .await?;
+    println!("Trade id: {trade_id}");
+
+    let store = Arc::new(trade_store());
+
+    tokio::spawn({
+        // process subscription
+        let bch_server = bch_server.clone();
+        let monerod = monerod.clone();
+        let monero_wallet = monero_wallet.clone();
+        let bch_wallet = bch_wallet.clone();
+        let trade_id = trade_id.clone();
+        let watcher = watcher.clone();
+        let store = store.clone();
+
+        async move {
+            let mut receiver = bch_server.subscribe();
+            let lifecycle = Lifecycle {
+                store: store.as_ref(),
+                bch: &bch_server,
+                watcher: &watcher,
+            };
+
+            loop {
+                let trade_ids = match receiver.recv().await {
+                    Ok(data) => watcher.on_notification(&bch_server, &data).await,
+                    Err(RecvError::Lagged(_)) => watcher.refresh(&bch_server).await,
+                    Err(RecvError::Closed) => break,
+                };
+                match trade_ids {
+                    Ok(trade_ids) if trade_ids.contains(&trade_id) => {}
+                    Ok(_) => continue,
+                    Err(e) => {
+                        println!("{:?}", e);
+                        continue;
+                    }
+                }
+
+                println!("Contract txs changed. Processing");
+                let txs = watcher.confirmed_txs(&trade_id, bch_min_confirmation);
+                let mempool_txs = match bch_mempool {
+                    true => watcher.mempool_txs(&trade_id),
+                    false => Vec::new(),
+                };
+
+                let mut trade = match store.load(&trade_id).await {
+                    Ok(trade) => trade,
+                    // archived by the main loop
+                    Err(persist::Error::NotFound) => break,
+                    Err(e) => {
+                        println!("Cannot load trade: {e:?}");
+                        continue;
+                    }
+                };
+                match trade.config.swap {
+                    SwapWrapper::Alice(_) => {}
+                    SwapWrapper::Bob(bob) => {
+                        let mut runner = bob::Runner {
+                            inner: bob,
+                            trade_id: trade_id.clone(),
+                            bch: &bch_server,
+                            monerod: &monerod,
+                            monero_wallet: &monero_wallet,
+                            bch_wallet: bch_wallet.as_deref(),
+                            min_bch_conf: bch_min_confirmation,
+                            bch_recv_key: trade.config.refund_private_key,
+                        };
+                        runner.process_bch_txs(txs).await;
+                        runner.process_bch_mempool_txs(mempool_txs).await;
+                        trade.config.swap = SwapWrapper::Bob(runner.inner);
+                        if let Err(e) = lifecycle.save(trade).await {
+                            println!("Cannot save trade: {e:?}");
+                        }
+                    }
+                };
+            }
+        }
+    });
+
+    // the loop below loads it on each round
+    store
+        .create(
+            &trade_id,
+            Config {
+                version: VERSION,
+                swap,
+                refund_private_key: refund_privkey,
+                completion: None,
+            },
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot save trade: {e:?}"))?;
+
+    println!("========================================");
+
+    let lifecycle = Lifecycle {
+        store: store.as_ref(),
+        bch: &bch_server,
+        watcher: &watcher,
+    };
+    loop {
+        let mut trade = match store.load(&trade_id).await {
+            Ok(trade) => trade,
+            // archived once over
+            Err(persist::Error::NotFound) => return Ok(()),
+            Err(e) => return Err(anyhow::anyhow!("Cannot load trade: {e:?}")),
+        };
+        match trade.config.swap {
+            SwapWrapper::Alice(_) => {}
+            SwapWrapper::Bob(inner) => {
+                let transition = inner.get_transition();
+
+                if let Some(contract_pair) = inner.get_contract_pair() {
+                    // no-op once subscribed
+                    if let Err(e) = watcher.watch(&bch_server, &trade_id, &contract_pair).await {
+                        println!("{:?}", e);
+                    }
+                }
+
+                let mut runner = bob::Runner {
+                    inner,
+                    trade_id: trade_id.clone(),
+                    bch: &bch_server,
+                    monerod: &monerod,
+                    monero_wallet: &monero_wallet,
+                    bch_wallet: bch_wallet.as_deref(),
+                    min_bch_conf: bch_min_confirmation,
+                    bch_recv_key: trade.config.refund_private_key,
+                };
+                if let Err(e) = runner.check_xmr().await {
+                    println!("{:?}", e);
+                }
+                trade.config.swap = SwapWrapper::Bob(runner.inner);
+                match lifecycle.save(trade).await {
+                    Ok(Some(_)) => return Ok(()),
+                    Ok(None) => {}
+                    Err(e) => println!("Cannot save trade: {e:?}"),
+                }
+
+                if let Some(transition) = transition {
+                    if let Err(e) =
+                        send_transition(&req_client, server_url, &trade_id, &transition).await
+                    {
+                        println!("{:?}", e);
+                        sleep(Duration::from_secs(10)).await;
+                    }
+                }
+            }
+        };
+
+        match get_server_transition(&req_client, server_url, &trade_id).await {
+            Err(e) => println!("============= {:?}", e),
+            Ok(transition) => match transition {
+                None => {
+                    sleep(Duration::from_secs(5)).await;
+                }
+                Some(transition) => {
+                    let mut trade = match store.load(&trade_id).await {
+                        Ok(trade) => trade,
+                        Err(persist::Error::NotFound) => return Ok(()),
+                        Err(e) => return Err(anyhow::anyhow!("Cannot load trade: {e:?}")),
+                    };
+                    match trade.config.swap {
+                        SwapWrapper::Bob(bob) => {
+                            let mut runner = bob::Runner {
+                                inner: bob,
+                                trade_id: trade_id.clone(),
+                                bch: &bch_server,
+                                monerod: &monerod,
+                                monero_wallet: &monero_wallet,
+                                bch_wallet: bch_wallet.as_deref(),
+                                min_bch_conf: bch_min_confirmation,
+                                bch_recv_key: trade.config.refund_private_key,
+                            };
+                            let result = runner.pub_transition(transition).await;
+                            trade.config.swap = SwapWrapper::Bob(runner.inner);
+                            match lifecycle.save(trade).await {
+                                Ok(Some(_)) => return Ok(()),
+                                Ok(None) => {}
+                                Err(e) => println!("Cannot save trade: {e:?}"),
+                            }
+                            result?;
+                        }
+                        SwapWrapper::Alice(_) => {}
+                    }
+                }
+            },
+        };
+
+        sleep(Duration::from_secs(5)).await;
+    }
+}
@@ -0,0 +1,29 @@
+use protocol::config::NodeConfig;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    #[serde(flatten)]
+    pub node: NodeConfig,
+
+    pub server_url: String,
+    pub bch_amount_sats: u64,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            node: NodeConfig::default(),
+            server_url: "http://localhost:8080".to_owned(),
+            bch_amount_sats: 100000,
+        }
+    }
+}
+
+pub fn load() -> anyhow::Result<ClientConfig> {
+    let config: ClientConfig = protocol::config::load()?;
+    config.node.check_networks()?;
+    config.node.check_mining_fees()?;
+    Ok(config)
+}
@@ -1,117 +1,75 @@
 use std::{sync::Arc, time::Duration};
 
-use anyhow::bail;
-use reqwest::StatusCode;
-use serde_json::json;
-
 use protocol::{
     alice,
     bitcoincash::{self},
-    blockchain::{self},
-    keys::{
-        bitcoin::{self, random_private_key},
-        KeyPrivate,
-    },
-    monero::{self},
-    persist::{Config, TradePersist},
-    protocol::Swap,
-    protocol::{SwapEvents, SwapWrapper, Transition},
+    blockchain::{self, watch::ScriptWatcher, xmr::XmrWallet},
+    keys::{bitcoin::random_private_key, KeyPrivate},
+    lifecycle::Lifecycle,
+    monero_rpc,
+    persist::{self, Config, SwapStore, VERSION},
+    protocol::{Swap, DEFAULT_MINING_FEE},
+    protocol::{SwapEvents, SwapWrapper},
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex},
+    time::sleep,
 };
-use tokio::{fs, io::AsyncWriteExt, net::TcpStream, time::sleep};
-
-const BASE_URL: &str = "http://localhost:8080";
-
-async fn create_new_trade(
-    client: &reqwest::Client,
-    timelock1: u32,
-    timelock2: u32,
-    bch_amount: bitcoincash::Amount,
-    xmr_amount: monero::Amount,
-) -> anyhow::Result<String> {
-    let response = client
-        .post(format!("{BASE_URL}/trader"))
-        .json(&json!({
-           "path": "xmr->bch",
-           "timelock1": timelock1,
-           "timelock2": timelock2,
-           "bch_amount": bch_amount.to_sat(),
-           "xmr_amount": xmr_amount.as_pico()
-        }))
-        .send()
-        .await?;
-
-    match response.status() {
-        StatusCode::OK => {
-            let body = response.json::<serde_json::Value>().await?;
-            return Ok(body["trade_id"].as_str().unwrap().to_string());
-        }
-        code => {
-            let body = response.text().await?;
-            bail!("[ERROR]: {code} - {body}");
-        }
-    }
-}
-
-async fn get_server_transition(
-    client: &reqwest::Client,
-    trade_id: &str,
-) -> anyhow::Result<Option<Transition>> {
-    let response = client
-        .get(format!("{BASE_URL}/trader/{trade_id}"))
-        .send()
-        .await?;
-
-    match response.status() {
-        StatusCode::OK => Ok(response.json::<Option<Transition>>().await?),
-        code => {
-            let body = response.text().await?;
-            bail!("[ERROR]: {code} - {body}");
-        }
-    }
-}
 
-async fn send_transition(
-    client: &reqwest::Client,
-    trade_id: &str,
-    transition: &Transition,
-) -> anyhow::Result<()> {
-    let response = client
-        .patch(format!("{BASE_URL}/trader/{trade_id}"))
-        .json(transition)
-        .send()
-        .await?;
-
-    match response.status() {
-        StatusCode::OK => Ok(()),
-        code => {
-            let body = response.text().await?;
-            bail!("[ERROR] {code} - {body}");
-        }
-    }
-}
+use api::{create_new_trade, get_quote, get_server_transition, send_transition, trade_store};
 
-pub fn get_file_path(trade_id: &str) -> String {
-    format!("./.trades/ongoing/{trade_id}-client.json")
-}
+mod api;
+mod config;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let bch_min_confirmation = 1;
+    let config = config::load()?;
+    let node = config.node;
+    if let Some(passphrase) = &node.trade_passphrase {
+        persist::set_passphrase(passphrase);
+    }
+    let server_url = config.server_url.as_str();
+    let bch_min_confirmation = node.bch_min_conf;
+    let bch_mempool = node.bch_mempool;
+    let monero_network = node.monero_network;
+    let bch_network = node.bch_network;
+    let electrum_servers = node.electrum_servers()?;
+
+    // Refunded xmr are swept here
+    let xmr_recv = node.xmr_recv;
 
-    let fullcrum_tcp = "localhost:50001";
-    let monero_network = monero::Network::Mainnet;
-    let bch_network = bitcoin::Network::Regtest;
+    // Lock the xmr from this monero-wallet-rpc wallet
+    let xmr_wallet = node
+        .xmr_wallet
+        .map(|filename| Arc::new(XmrWallet::new(filename, node.xmr_wallet_password)));
 
     // ===================================================
 
     let req_client = reqwest::Client::new();
-    let socket = TcpStream::connect(fullcrum_tcp).await?;
-    let bch_server = Arc::new(blockchain::TcpElectrum::new(socket));
+    let bch_server = Arc::new(
+        blockchain::TcpElectrum::connect(
+            electrum_servers,
+            Duration::from_secs(node.electrum_timeout_secs),
+        )
+        .await?,
+    );
+
+    let monerod = Arc::new(
+        monero_rpc::RpcClientBuilder::new()
+            .build(node.monerod_addr)?
+            .daemon(),
+    );
+    let monero_wallet = Arc::new(Mutex::new(
+        monero_rpc::RpcClientBuilder::new()
+            .build(node.monero_wallet_addr)?
+            .wallet(),
+    ));
 
     println!("Subscribing for new block");
-    let _ = bch_server
-        .send("blockchain.headers.subscribe", json!([]))
-        .await?;
+    let tip = bch_server.headers_subscribe().await?;
+    println!("Current height: {}", tip.height);
+    let watcher = Arc::new(ScriptWatcher::new(bch_network));
+    watcher.sync_tip(&bch_server, &tip).await?;
     println!("========================================");
 
     println!("Generating new keys...");
@@ -121,30 +79,49 @@ async fn main() -> anyhow::Result<()> {
     let recv_addr = recv_pub.pubkey_hash();
     let recv_script = bitcoincash::Script::new_p2pkh(&recv_addr);
 
-    let timelock1 = 20;
-    let timelock2 = 20;
-
-    let bch_amount = bitcoincash::Amount::from_sat(100000);
-    let xmr_amount = monero::Amount::from_pico(100000);
-
-    let swap = alice::Alice {
-        state: alice::State::Init,
-        swap: Swap {
-            id: "".to_owned(),
-            keys: KeyPrivate::random(bch_network),
-
-            bch_amount,
-            xmr_amount,
-
-            xmr_network: monero_network,
-            bch_network,
-
-            bch_recv: recv_script,
-
-            timelock1,
-            timelock2,
-        },
-    };
+    let timelock1 = node.timelock1;
+    let timelock2 = node.timelock2;
+    let min_mining_fee = node.min_mining_fee;
+    let max_mining_fee = node.max_mining_fee;
+
+    println!("Requesting quote...");
+    let quote = get_quote(
+        &req_client,
+        server_url,
+        "xmr->bch",
+        bitcoincash::Amount::from_sat(config.bch_amount_sats),
+    )
+    .await?;
+    println!("Quote: {:?}", quote);
+    let bch_amount = quote.bch_amount;
+    let xmr_amount = quote.xmr_amount;
+
+    // Propose what the network currently asks, within our limits
+    let mining_fee = blockchain::estimate_mining_fee(&bch_server, 1)
+        .await
+        .unwrap_or(DEFAULT_MINING_FEE)
+        .clamp(min_mining_fee, max_mining_fee);
+
+    let swap = alice::Alice::new(Swap {
+        id: "".to_owned(),
+        keys: KeyPrivate::random(bch_network),
+
+        bch_amount,
+        xmr_amount,
+
+        xmr_network: monero_network,
+        bch_network,
+
+        bch_recv: recv_script,
+        xmr_recv,
+
+        timelock1,
+        timelock2,
+
+        mining_fee,
+        min_mining_fee,
+        max_mining_fee,
+    });
 
     let string_json = serde_json::to_string_pretty(&swap.swap.keys).unwrap();
     println!("Private Keys: {string_json}");
@@ -155,76 +132,157 @@ async fn main() -> anyhow::Result<()> {
     println!("========================================");
 
     println!("Creating new trade...");
-    let trade_id =
-        create_new_trade(&req_client, timelock1, timelock2, bch_amount, xmr_amount).await?;
+    let trade_id = create_new_trade(
+        &req_client,
+        server_url,
+        "xmr->bch",
+        timelock1,
+        timelock2,
+        &quote,
+    )
+    .await?;
     println!("Trade id: {trade_id}");
 
+    let store = Arc::new(trade_store());
+
     tokio::spawn({
         // process subscription
         let bch_server = bch_server.clone();
+        let monerod = monerod.clone();
+        let monero_wallet = monero_wallet.clone();
+        let xmr_wallet = xmr_wallet.clone();
         let trade_id = trade_id.clone();
+        let watcher = watcher.clone();
+        let store = store.clone();
 
         async move {
             let mut receiver = bch_server.subscribe();
+            let lifecycle = Lifecycle {
+                store: store.as_ref(),
+                bch: &bch_server,
+                watcher: &watcher,
+            };
 
             loop {
-                let data = receiver.recv().await.unwrap();
-                let data = serde_json::from_str::<serde_json::Value>(&data).unwrap();
-
-                let method = data["method"].as_str().unwrap();
-                if method != "blockchain.headers.subscribe" {
-                    eprintln!("Unknown method: {method}");
-                    continue;
+                let trade_ids = match receiver.recv().await {
+                    Ok(data) => watcher.on_notification(&bch_server, &data).await,
+                    Err(RecvError::Lagged(_)) => watcher.refresh(&bch_server).await,
+                    Err(RecvError::Closed) => break,
+                };
+                match trade_ids {
+                    Ok(trade_ids) if trade_ids.contains(&trade_id) => {}
+                    Ok(_) => continue,
+                    Err(e) => {
+                        println!("{:?}", e);
+                        continue;
+                    }
                 }
 
-                println!("New block found. Rescanning addresses");
+                println!("Contract txs changed. Processing");
+                let txs = watcher.confirmed_txs(&trade_id, bch_min_confirmation);
+                let mempool_txs = match bch_mempool {
+                    true => watcher.mempool_txs(&trade_id),
+                    false => Vec::new(),
+                };
 
-                let mut trade = TradePersist::restore(get_file_path(&trade_id))
-                    .await
-                    .unwrap();
+                let mut trade = match store.load(&trade_id).await {
+                    Ok(trade) => trade,
+                    // archived by the main loop
+                    Err(persist::Error::NotFound) => break,
+                    Err(e) => {
+                        println!("Cannot load trade: {e:?}");
+                        continue;
+                    }
+                };
                 match trade.config.swap {
                     SwapWrapper::Bob(_) => {}
                     SwapWrapper::Alice(alice) => {
                         let mut runner = alice::Runner {
                             inner: alice,
+                            trade_id: trade_id.clone(),
                             bch: &bch_server,
+                            monerod: &monerod,
+                            monero_wallet: &monero_wallet,
                             min_bch_conf: bch_min_confirmation,
+                            bch_recv_key: trade.config.refund_private_key,
+                            xmr_wallet: xmr_wallet.as_deref(),
                         };
-                        let _ = runner.check_bch().await;
+                        runner.process_bch_txs(txs).await;
+                        runner.process_bch_mempool_txs(mempool_txs).await;
                         trade.config.swap = SwapWrapper::Alice(runner.inner);
-                        trade.save().await;
+                        if let Err(e) = lifecycle.save(trade).await {
+                            println!("Cannot save trade: {e:?}");
+                        }
                     }
                 };
             }
         }
     });
 
-    let serialized = serde_json::to_vec_pretty(&Config {
-        swap,
-        refund_private_key: recv_privkey,
-    })?;
-    fs::OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(get_file_path(&trade_id))
-        .await?
-        .write(&serialized)
-        .await?;
+    // the loop below loads it on each round
+    store
+        .create(
+            &trade_id,
+            Config {
+                version: VERSION,
+                swap,
+                refund_private_key: recv_privkey,
+                completion: None,
+            },
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot save trade: {e:?}"))?;
 
     println!("========================================");
 
+    let lifecycle = Lifecycle {
+        store: store.as_ref(),
+        bch: &bch_server,
+        watcher: &watcher,
+    };
     loop {
-        let trade = TradePersist::restore(get_file_path(&trade_id))
-            .await
-            .unwrap();
-        match &trade.config.swap {
+        let mut trade = match store.load(&trade_id).await {
+            Ok(trade) => trade,
+            // archived once over
+            Err(persist::Error::NotFound) => return Ok(()),
+            Err(e) => return Err(anyhow::anyhow!("Cannot load trade: {e:?}")),
+        };
+        match trade.config.swap {
             SwapWrapper::Bob(_) => {}
             SwapWrapper::Alice(inner) => {
                 let transition = inner.get_transition();
-                drop(trade);
+
+                if let Some(contract_pair) = inner.get_contract_pair() {
+                    // no-op once subscribed
+                    if let Err(e) = watcher.watch(&bch_server, &trade_id, &contract_pair).await {
+                        println!("{:?}", e);
+                    }
+                }
+
+                let mut runner = alice::Runner {
+                    inner,
+                    trade_id: trade_id.clone(),
+                    bch: &bch_server,
+                    monerod: &monerod,
+                    monero_wallet: &monero_wallet,
+                    min_bch_conf: bch_min_confirmation,
+                    bch_recv_key: trade.config.refund_private_key,
+                    xmr_wallet: xmr_wallet.as_deref(),
+                };
+                if let Err(e) = runner.check_xmr().await {
+                    println!("{:?}", e);
+                }
+                trade.config.swap = SwapWrapper::Alice(runner.inner);
+                match lifecycle.save(trade).await {
+                    Ok(Some(_)) => return Ok(()),
+                    Ok(None) => {}
+                    Err(e) => println!("Cannot save trade: {e:?}"),
+                }
 
                 if let Some(transition) = transition {
-                    if let Err(e) = send_transition(&req_client, &trade_id, &transition).await {
+                    if let Err(e) =
+                        send_transition(&req_client, server_url, &trade_id, &transition).await
+                    {
                         println!("{:?}", e);
                         sleep(Duration::from_secs(10)).await;
                     }
@@ -232,26 +290,38 @@ async fn main() -> anyhow::Result<()> {
             }
         };
 
-        match get_server_transition(&req_client, &trade_id).await {
+        match get_server_transition(&req_client, server_url, &trade_id).await {
             Err(e) => println!("============= {:?}", e),
             Ok(transition) => match transition {
                 None => {
                     sleep(Duration::from_secs(5)).await;
                 }
                 Some(transition) => {
-                    let mut trade = TradePersist::restore(get_file_path(&trade_id))
-                        .await
-                        .unwrap();
+                    let mut trade = match store.load(&trade_id).await {
+                        Ok(trade) => trade,
+                        Err(persist::Error::NotFound) => return Ok(()),
+                        Err(e) => return Err(anyhow::anyhow!("Cannot load trade: {e:?}")),
+                    };
                     match trade.config.swap {
                         SwapWrapper::Alice(alice) => {
                             let mut runner = alice::Runner {
                                 inner: alice,
+                                trade_id: trade_id.clone(),
                                 min_bch_conf: bch_min_confirmation,
+                                bch_recv_key: trade.config.refund_private_key,
+                                xmr_wallet: xmr_wallet.as_deref(),
                                 bch: &bch_server,
+                                monerod: &monerod,
+                                monero_wallet: &monero_wallet,
                             };
-                            runner.pub_transition(transition).await?;
+                            let result = runner.pub_transition(transition).await;
                             trade.config.swap = SwapWrapper::Alice(runner.inner);
-                            trade.save().await;
+                            match lifecycle.save(trade).await {
+                                Ok(Some(_)) => return Ok(()),
+                                Ok(None) => {}
+                                Err(e) => println!("Cannot save trade: {e:?}"),
+                            }
+                            result?;
                         }
                         SwapWrapper::Bob(_) => {}
                     }
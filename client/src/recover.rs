@@ -1,34 +1,96 @@
-use std::{env, sync::Arc};
+use std::{env, sync::Arc, time::Duration};
 
-use protocol::{alice, blockchain, persist::TradePersist, protocol::SwapWrapper};
-use tokio::net::TcpStream;
+use protocol::{
+    alice,
+    blockchain::{self, watch::ScriptWatcher, xmr::XmrWallet},
+    lifecycle::Lifecycle,
+    monero_rpc,
+    persist::{self, FileStore, SwapStore},
+    protocol::SwapWrapper,
+};
+use tokio::sync::Mutex;
 
-pub fn get_file_path(trade_id: &str) -> String {
-    format!("./.trades/ongoing/{trade_id}-client.json")
+mod config;
+
+pub fn trade_store() -> FileStore {
+    FileStore::new("./.trades", "-client.json")
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // recover <trade_id> [--key value]...
     let trade_id = env::args().nth(1).expect("Trade id required");
 
-    let fullcrum_tcp = "localhost:50001";
-    let socket = TcpStream::connect(fullcrum_tcp).await?;
-    let bch_server = Arc::new(blockchain::TcpElectrum::new(socket));
+    let node = config::load()?.node;
+    if let Some(passphrase) = &node.trade_passphrase {
+        persist::set_passphrase(passphrase);
+    }
+
+    let bch_server = Arc::new(
+        blockchain::TcpElectrum::connect(
+            node.electrum_servers()?,
+            Duration::from_secs(node.electrum_timeout_secs),
+        )
+        .await?,
+    );
+
+    // txs are only trusted with a proof against our header chain
+    let tip = bch_server.headers_subscribe().await?;
+    let watcher = ScriptWatcher::new(node.bch_network);
+    watcher.sync_tip(&bch_server, &tip).await?;
+
+    let monerod = monero_rpc::RpcClientBuilder::new()
+        .build(node.monerod_addr)?
+        .daemon();
+    let monero_wallet = Mutex::new(
+        monero_rpc::RpcClientBuilder::new()
+            .build(node.monero_wallet_addr)?
+            .wallet(),
+    );
+
+    let xmr_wallet = node
+        .xmr_wallet
+        .map(|filename| XmrWallet::new(filename, node.xmr_wallet_password));
 
-    let mut trade = TradePersist::restore(get_file_path(&trade_id))
-        .await
-        .unwrap();
+    let store = trade_store();
+    let mut trade = store.load(&trade_id).await.unwrap();
     match trade.config.swap {
         SwapWrapper::Bob(_) => {}
         SwapWrapper::Alice(inner) => {
+            if let Some(contract_pair) = inner.get_contract_pair() {
+                watcher
+                    .watch(&bch_server, &trade_id, &contract_pair)
+                    .await?;
+            }
+            let txs = watcher.confirmed_txs(&trade_id, 0);
+            let mempool_txs = match node.bch_mempool {
+                true => watcher.mempool_txs(&trade_id),
+                false => Vec::new(),
+            };
+
             let mut runner = alice::Runner {
                 inner,
+                trade_id,
                 bch: &bch_server,
+                monerod: &monerod,
+                monero_wallet: &monero_wallet,
                 min_bch_conf: 0,
+                bch_recv_key: trade.config.refund_private_key,
+                xmr_wallet: xmr_wallet.as_ref(),
             };
-            let _ = runner.check_bch().await;
+            runner.process_bch_txs(txs).await;
+            runner.process_bch_mempool_txs(mempool_txs).await;
+            let _ = runner.check_xmr().await;
             trade.config.swap = SwapWrapper::Alice(runner.inner);
-            trade.save().await;
+            let lifecycle = Lifecycle {
+                store: &store,
+                bch: &bch_server,
+                watcher: &watcher,
+            };
+            lifecycle
+                .save(trade)
+                .await
+                .map_err(|e| anyhow::anyhow!("Cannot save trade: {e:?}"))?;
         }
     };
 
@@ -0,0 +1,45 @@
+use std::env;
+
+use anyhow::bail;
+use protocol::persist::{file, Keyring, SqliteStore, SwapStore};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // reencrypt <trade file>... | reencrypt --sqlite <path>
+    // read with OLD_TRADE_PASSPHRASE, written with TRADE_PASSPHRASE, unset is plain json
+    let args: Vec<String> = env::args().skip(1).collect();
+    let sqlite = match args.as_slice() {
+        [] => bail!("Usage: reencrypt <trade file>... | reencrypt --sqlite <path>"),
+        [flag, path] if flag == "--sqlite" => Some(path.clone()),
+        [flag, ..] if flag == "--sqlite" => bail!("Usage: reencrypt --sqlite <path>"),
+        _ => None,
+    };
+
+    let old = env::var("OLD_TRADE_PASSPHRASE")
+        .ok()
+        .map(|passphrase| Keyring::new(&passphrase));
+    let new = env::var("TRADE_PASSPHRASE")
+        .ok()
+        .map(|passphrase| Keyring::new(&passphrase));
+
+    if let Some(path) = sqlite {
+        let store = match SqliteStore::open(&path) {
+            Ok(store) => store,
+            Err(e) => bail!("Cannot open {path}: {e:?}"),
+        };
+        if let Err(e) = store.reencrypt(old.as_ref(), new.as_ref()).await {
+            bail!("Cannot reencrypt {path}: {e:?}");
+        }
+        println!("Reencrypted {path}");
+        return Ok(());
+    }
+
+    for file_path in args {
+        if let Err(e) = file::reencrypt(&file_path, old.as_ref(), new.as_ref()).await {
+            bail!("Cannot reencrypt {file_path}: {e:?}");
+        }
+        println!("Reencrypted {file_path}");
+    }
+
+    Ok(())
+}
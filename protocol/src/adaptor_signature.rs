@@ -55,23 +55,22 @@ impl AdaptorSignature {
         adaptor.decrypt_signature(&decryption_key, encrypted_sig)
     }
 
+    /// None if `sig` is not the decryption of `enc_sig`
     pub fn recover_decryption_key(
         pubkey: bitcoincash::PublicKey,
         sig: Signature,
         enc_sig: EncryptedSignature,
-    ) -> monero::PrivateKey {
+    ) -> Option<monero::PrivateKey> {
         let adaptor: Adaptor<Transcript, NonceGen> = Adaptor::default();
         let pubkey: Point = fun::Point::from_bytes(pubkey.inner.serialize())
             .expect("failed to convert PublicKey -> Point");
 
-        let key_reversed = adaptor
-            .recover_decryption_key(&pubkey, &sig, &enc_sig)
-            .unwrap();
+        let key_reversed = adaptor.recover_decryption_key(&pubkey, &sig, &enc_sig)?;
 
         let mut big_edian: [u8; 32] = key_reversed.to_bytes();
         big_edian.reverse();
         let little_edian = big_edian;
-        monero::PrivateKey::from_slice(&little_edian).unwrap()
+        monero::PrivateKey::from_slice(&little_edian).ok()
     }
 }
 
@@ -114,7 +113,7 @@ mod test {
 
         // bob get the decsig on bch tx, and recover alice priv_spend
         let alice_spend_recovered =
-            AdaptorSignature::recover_decryption_key(alicepub.spend_bch, dec_sig, enc_sig);
+            AdaptorSignature::recover_decryption_key(alicepub.spend_bch, dec_sig, enc_sig).unwrap();
 
         assert_eq!(
             alice_spend_recovered.to_string(),
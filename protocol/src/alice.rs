@@ -2,23 +2,25 @@ use std::fmt;
 
 use anyhow::bail;
 use bitcoin_hashes::{sha256::Hash as sha256, Hash};
-use bitcoincash::{
-    consensus::Encodable, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut,
-};
+use bitcoincash::{OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut};
 use ecdsa_fun::adaptor::EncryptedSignature;
-use hex::ToHex;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use tokio::sync::Mutex;
 
 use crate::{
     adaptor_signature::AdaptorSignature,
     bitcoincash::secp256k1::ecdsa,
-    blockchain::{scan_address_conf_tx, TcpElectrum},
-    contract::{ContractPair, TransactionType},
+    blockchain::{
+        broadcaster::{self, PendingTx},
+        xmr::{self, XmrWallet},
+        TcpElectrum,
+    },
+    contract::{timelock::Confirmation, ContractPair, TransactionType},
     keys::{KeyPublic, KeyPublicWithoutProof},
     proof,
-    protocol::{Action, Error, Swap, SwapEvents, Transition},
+    protocol::{Action, Error, Outcome, Swap, SwapEvents, Transition, XmrLockTx},
     utils::{get_signature, monero_key_pair, monero_view_pair},
+    wallet::BchWallet,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,6 +42,8 @@ pub struct Value1 {
     contract_pair: ContractPair,
     #[serde(with = "monero_view_pair")]
     shared_keypair: monero::ViewPair,
+    #[serde(default)]
+    xmr_restore_height: u64,
 
     outpoint: OutPoint,
 }
@@ -53,6 +57,8 @@ pub struct Value2 {
     contract_pair: ContractPair,
     #[serde(with = "monero_view_pair")]
     shared_keypair: monero::ViewPair,
+    #[serde(default)]
+    xmr_restore_height: u64,
     outpoint: OutPoint,
 
     dec_sig: ecdsa::Signature,
@@ -65,32 +71,88 @@ pub enum State {
     ContractMatch(Value0),
     BchLocked(Value1),
     ValidEncSig(Value2),
+    /// Bob moved the swaplock to refund but did not claim it before timelock2.
+    /// Outpoint is the refund contract utxo
+    ProceedPunish(Value1),
     Refund(
         monero::Address,
         #[serde(with = "monero_key_pair")] monero::KeyPair,
+        u64,
     ),
+    /// Xmr from State::Refund was sent to swap.xmr_recv
+    XmrSwept(Vec<String>),
+    /// The swaplock was spent to alice with bob's decrypted signature
+    SwapSuccess,
+    /// The refund contract was spent to alice after timelock2
+    Punished,
 }
 
-impl fmt::Display for State {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl State {
+    pub fn name(&self) -> &'static str {
         match self {
-            State::Init => write!(f, "AliceState:Init"),
-            State::WithBobKeys(_) => write!(f, "AliceState:WithBobKeys"),
-            State::ContractMatch(_) => write!(f, "AliceState:ContractMatch"),
-            State::BchLocked(_) => write!(f, "AliceState:BchLocked"),
-            State::ValidEncSig(_) => write!(f, "AliceState:ValidEncSig"),
-            State::Refund(_, _) => write!(f, "AliceState:Refund"),
+            State::Init => "Init",
+            State::WithBobKeys(_) => "WithBobKeys",
+            State::ContractMatch(_) => "ContractMatch",
+            State::BchLocked(_) => "BchLocked",
+            State::ValidEncSig(_) => "ValidEncSig",
+            State::ProceedPunish(_) => "ProceedPunish",
+            State::Refund(_, _, _) => "Refund",
+            State::XmrSwept(_) => "XmrSwept",
+            State::SwapSuccess => "SwapSuccess",
+            State::Punished => "Punished",
         }
     }
 }
 
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AliceState:{}", self.name())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Alice {
     pub state: State,
     pub swap: Swap,
+    #[serde(default)]
+    pub xmr_lock_tx: Option<XmrLockTx>,
+    /// Contract spends sent but not mined yet
+    #[serde(default)]
+    pub pending_txs: Vec<PendingTx>,
+    /// Why the trade was dropped on an Action::SafeDelete
+    #[serde(default)]
+    pub aborted: Option<String>,
 }
 
 impl Alice {
+    pub fn new(swap: Swap) -> Self {
+        Alice {
+            state: State::Init,
+            swap,
+            xmr_lock_tx: None,
+            pending_txs: Vec::new(),
+            aborted: None,
+        }
+    }
+
+    /// Some once nothing is left to send, sweep or watch
+    pub fn outcome(&self) -> Option<Outcome> {
+        if let Some(reason) = &self.aborted {
+            return Some(Outcome::Aborted(reason.clone()));
+        }
+        if !self.pending_txs.is_empty() || self.get_xmr_sweep().is_some() {
+            return None;
+        }
+
+        match self.state {
+            State::SwapSuccess => Some(Outcome::Success),
+            // an unswept refund is still in the shared xmr wallet
+            State::XmrSwept(_) => Some(Outcome::Refunded),
+            State::Punished => Some(Outcome::Punished),
+            _ => None,
+        }
+    }
+
     pub fn get_public_keys(&self) -> KeyPublic {
         KeyPublic::from(self.swap.keys.clone())
     }
@@ -110,6 +172,7 @@ impl Alice {
         let (spend, recv) = match &self.state {
             State::ContractMatch(props) => (props.bob_keys.spend_bch, &props.bob_bch_recv),
             State::BchLocked(props) => (props.bob_keys.spend_bch, &props.bob_bch_recv),
+            State::ProceedPunish(props) => (props.bob_keys.spend_bch, &props.bob_bch_recv),
             _ => return None,
         };
 
@@ -125,6 +188,7 @@ impl Alice {
             State::ContractMatch(v) => Some(v.contract_pair),
             State::BchLocked(v) => Some(v.contract_pair),
             State::ValidEncSig(v) => Some(v.contract_pair),
+            State::ProceedPunish(v) => Some(v.contract_pair),
             _ => None,
         }
     }
@@ -158,6 +222,48 @@ impl Alice {
 
         None
     }
+
+    pub fn get_xmr_sweep(&self) -> Option<Action> {
+        match (&self.state, self.swap.xmr_recv) {
+            (State::Refund(_, keypair, restore_height), Some(destination)) => {
+                Some(Action::SweepXmr {
+                    keypair: *keypair,
+                    restore_height: *restore_height,
+                    destination,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Spend the refund contract to alice after timelock2.
+    /// Bob had the chance to claim it, but didn't
+    pub fn get_refund_to_alice_tx(&self) -> Option<Transaction> {
+        if let State::ProceedPunish(props) = &self.state {
+            let unlocker = props.contract_pair.refund.unlocking_script(&[]);
+
+            let mining_fee = props.contract_pair.mining_fee;
+            let transaction = Transaction {
+                version: 2,
+                lock_time: PackedLockTime(0), // TODO: Should we use current time?
+                input: vec![TxIn {
+                    sequence: Sequence(props.contract_pair.refund.timelock),
+                    previous_output: props.outpoint,
+                    script_sig: Script::from(unlocker),
+                    ..Default::default()
+                }],
+                output: vec![TxOut {
+                    value: self.swap.bch_amount.to_sat() - (mining_fee * 2),
+                    script_pubkey: self.swap.bch_recv.clone(),
+                    token: None,
+                }],
+            };
+
+            return Some(transaction);
+        }
+
+        None
+    }
 }
 
 #[async_trait::async_trait]
@@ -167,17 +273,88 @@ impl SwapEvents for Alice {
     fn transition(mut self, transition: Transition) -> (Self::State, Vec<Action>, Option<Error>) {
         println!("{} - {}", &self.state, &transition);
 
+        if let Transition::SetXmrRestoreHeight(height) = transition {
+            match &mut self.state {
+                State::BchLocked(ref mut v) => v.xmr_restore_height = height,
+                State::ValidEncSig(ref mut v) => v.xmr_restore_height = height,
+                State::ProceedPunish(ref mut v) => v.xmr_restore_height = height,
+                _ => {}
+            }
+            return (self, vec![], None);
+        }
+
+        if let Transition::XmrLocked(lock_tx) = transition {
+            if let State::BchLocked(_) = self.state {
+                self.xmr_lock_tx = Some(lock_tx);
+            }
+            return (self, vec![], None);
+        }
+
+        // The key is in the signature, so no need to wait for confirmations
+        if let Transition::BchMempoolTx(transaction) = transition {
+            let reveals_key = match &self.state {
+                State::BchLocked(props) | State::ProceedPunish(props) => matches!(
+                    props.contract_pair.analyze_tx(&transaction),
+                    Some((_, TransactionType::ToBob))
+                ),
+                _ => false,
+            };
+
+            if !reveals_key {
+                return (self, vec![], None);
+            }
+            return self.transition(Transition::BchConfirmedTx(
+                transaction,
+                Confirmation::default(),
+            ));
+        }
+
+        // Bob moved the swaplock to refund before our spend was mined,
+        // the dec sig is useless now. Follow the refund like from BchLocked
+        if let (State::ValidEncSig(props), Transition::BchConfirmedTx(transaction, _)) =
+            (&self.state, &transition)
+        {
+            if let Some((_, TransactionType::ToRefund)) =
+                props.contract_pair.analyze_tx(transaction)
+            {
+                self.state = State::BchLocked(Value1 {
+                    bob_keys: props.bob_keys.clone(),
+                    bob_bch_recv: props.bob_bch_recv.clone(),
+                    contract_pair: props.contract_pair.clone(),
+                    shared_keypair: props.shared_keypair,
+                    xmr_restore_height: props.xmr_restore_height,
+                    outpoint: props.outpoint,
+                });
+            }
+        }
+
         let current_state = self.state.clone();
         match (current_state, transition) {
-            (State::Init, Transition::Msg0 { keys, receiving }) => {
+            (
+                State::Init,
+                Transition::Msg0 {
+                    keys,
+                    receiving,
+                    mining_fee,
+                },
+            ) => {
                 let is_valid_keys = proof::verify(&keys.proof, keys.spend_bch, keys.monero_spend);
                 if !is_valid_keys {
                     return (self, vec![Action::SafeDelete], Some(Error::InvalidProof));
                 }
 
+                let Some(mining_fee) = self.swap.negotiate_mining_fee(mining_fee) else {
+                    return (
+                        self,
+                        vec![Action::SafeDelete],
+                        Some(Error::InvalidMiningFee),
+                    );
+                };
+                self.swap.mining_fee = mining_fee;
+
                 let secp = bitcoincash::secp256k1::Secp256k1::signing_only();
                 let contract = ContractPair::create(
-                    1000,
+                    mining_fee,
                     receiving.clone().into_bytes(),
                     keys.ves.clone(),
                     self.swap.bch_recv.to_bytes().clone(),
@@ -244,6 +421,7 @@ impl SwapEvents for Alice {
                             bob_bch_recv: props.bob_bch_recv,
                             contract_pair: props.contract_pair,
                             shared_keypair: props.shared_keypair,
+                            xmr_restore_height: 0,
 
                             outpoint,
                         });
@@ -259,10 +437,11 @@ impl SwapEvents for Alice {
                 }
             }
 
-            (State::BchLocked(props), Transition::BchConfirmedTx(transaction, _)) => {
-                if let Some((_, TransactionType::ToBob)) =
-                    props.contract_pair.analyze_tx(&transaction)
-                {
+            (
+                State::BchLocked(props) | State::ProceedPunish(props),
+                Transition::BchConfirmedTx(transaction, confirmation),
+            ) => match props.contract_pair.analyze_tx(&transaction) {
+                Some((_, TransactionType::ToBob)) => {
                     let script = transaction.input[0].script_sig.clone();
                     let decsig = match get_signature(script) {
                         Some(sig) => {
@@ -274,12 +453,19 @@ impl SwapEvents for Alice {
                         _ => return (self, vec![], Some(Error::InvalidTransaction)),
                     };
 
-                    let bob_spend = AdaptorSignature::recover_decryption_key(
+                    let Some(bob_spend) = AdaptorSignature::recover_decryption_key(
                         props.bob_keys.spend_bch,
                         decsig,
                         self.get_refunc_enc_sig()
-                            .expect("Enc sig should be open at State::BchLocked"),
-                    );
+                            .expect("Enc sig should be open at current state"),
+                    ) else {
+                        return (self, vec![], Some(Error::InvalidTransaction));
+                    };
+                    if monero::PublicKey::from_private_key(&bob_spend)
+                        != props.bob_keys.monero_spend
+                    {
+                        return (self, vec![], Some(Error::InvalidTransaction));
+                    }
 
                     let key_pair = monero::KeyPair {
                         view: props.shared_keypair.view,
@@ -289,18 +475,49 @@ impl SwapEvents for Alice {
                     self.state = State::Refund(
                         monero::Address::from_keypair(self.swap.xmr_network, &key_pair),
                         key_pair,
+                        props.xmr_restore_height,
                     );
 
-                    return (self, vec![], None);
+                    let actions = self.get_xmr_sweep().into_iter().collect();
+                    return (self, actions, None);
                 }
+                Some((outpoint, TransactionType::ToRefund)) => {
+                    // Bob must claim the refund before its timelock expires,
+                    // after that alice can take the bch
+                    if !confirmation.is_unlocked() {
+                        return (self, vec![], None);
+                    }
 
-                return (self, vec![], None);
+                    // Queued once by the runner, then retried until mined
+                    self.state = State::ProceedPunish(Value1 { outpoint, ..props });
+                    (self, vec![Action::UnlockBchPunish], None)
+                }
+                Some((_, TransactionType::RefundToAlice)) => {
+                    self.state = State::Punished;
+                    (self, vec![Action::TradeSuccess], None)
+                }
+                _ => (self, vec![], None),
+            },
+
+            (State::Refund(..), Transition::XmrSwept(tx_hashes)) => {
+                self.state = State::XmrSwept(tx_hashes);
+                (self, vec![Action::TradeSuccess], None)
             }
 
             (State::ValidEncSig(_), Transition::EncSig(_)) => {
                 return (self, vec![], None);
             }
 
+            (State::ValidEncSig(props), Transition::BchConfirmedTx(transaction, _)) => {
+                match props.contract_pair.analyze_tx(&transaction) {
+                    Some((_, TransactionType::SwapLockToAlice)) => {
+                        self.state = State::SwapSuccess;
+                        (self, vec![Action::TradeSuccess], None)
+                    }
+                    _ => (self, vec![], None),
+                }
+            }
+
             (State::BchLocked(props), Transition::EncSig(encsig)) => {
                 let dec_sig = AdaptorSignature::decrypt_signature(
                     &self.swap.keys.monero_spend,
@@ -329,6 +546,7 @@ impl SwapEvents for Alice {
                     bob_bch_recv: props.bob_bch_recv,
                     contract_pair: props.contract_pair,
                     shared_keypair: props.shared_keypair,
+                    xmr_restore_height: props.xmr_restore_height,
                     outpoint: props.outpoint,
                     dec_sig,
                 });
@@ -343,7 +561,11 @@ impl SwapEvents for Alice {
             State::Init => {
                 let keys = self.get_public_keys();
                 let receiving = self.swap.bch_recv.clone();
-                Some(Transition::Msg0 { keys, receiving })
+                Some(Transition::Msg0 {
+                    keys,
+                    receiving,
+                    mining_fee: self.swap.mining_fee,
+                })
             }
             State::WithBobKeys(_) => {
                 let (bch_address, xmr_address) = self.get_contract().unwrap();
@@ -363,32 +585,69 @@ impl SwapEvents for Alice {
 
 pub struct Runner<'a> {
     pub inner: Alice,
+    pub trade_id: String,
     pub bch: &'a TcpElectrum,
-    // pub monerod: &'a monero_rpc::DaemonJsonRpcClient,
-    // pub monero_wallet: &'a Mutex<monero_rpc::WalletClient>,
+    pub monerod: &'a monero_rpc::DaemonJsonRpcClient,
+    pub monero_wallet: &'a Mutex<monero_rpc::WalletClient>,
     pub min_bch_conf: u32,
+    /// Lock the xmr automatically when set
+    pub xmr_wallet: Option<&'a XmrWallet>,
+    /// Key of swap.bch_recv, to pay for stuck spends with a child
+    pub bch_recv_key: bitcoincash::PrivateKey,
 }
 
 impl Runner<'_> {
-    pub async fn check_bch(&mut self) -> anyhow::Result<()> {
-        let contract = self.inner.get_contract_pair();
-        if let Some(contract) = contract {
-            let swaplock = contract.swaplock.cash_address();
-            let refund = contract.refund.cash_address();
-            for address in [swaplock, refund].into_iter() {
-                let txs = scan_address_conf_tx(&self.bch, &address, self.min_bch_conf).await;
-                println!("{}txs address {}", txs.len(), address);
-                for (tx, conf) in txs {
-                    let _ = self
-                        .priv_transition(Transition::BchConfirmedTx(tx, conf))
-                        .await;
-                }
-            }
+    /// Retry the sweep while we are still holding the xmr.
+    /// Refunded outputs must unlock first, so the first attempt may fail
+    pub async fn check_xmr(&mut self) -> anyhow::Result<()> {
+        if let Some(Action::SweepXmr {
+            keypair,
+            restore_height,
+            destination,
+        }) = self.inner.get_xmr_sweep()
+        {
+            let tx_hashes = xmr::sweep_all(
+                self.monero_wallet,
+                format!("{}_spend", self.trade_id),
+                self.inner.swap.xmr_network,
+                keypair,
+                restore_height,
+                destination,
+            )
+            .await?;
+
+            self.priv_transition(Transition::XmrSwept(tx_hashes))
+                .await?;
         }
 
         Ok(())
     }
 
+    /// Feed the proven txs of the contracts from a `ScriptWatcher`
+    pub async fn process_bch_txs(&mut self, txs: Vec<(Transaction, Confirmation)>) {
+        for (tx, confirmation) in txs.iter() {
+            let _ = self
+                .priv_transition(Transition::BchConfirmedTx(tx.clone(), *confirmation))
+                .await;
+        }
+
+        let bch_recv = BchWallet::new(self.bch_recv_key, self.inner.swap.bch_network);
+        broadcaster::rebroadcast(
+            self.bch,
+            &self.trade_id,
+            &mut self.inner.pending_txs,
+            &txs,
+            &bch_recv,
+        )
+        .await;
+    }
+
+    pub async fn process_bch_mempool_txs(&mut self, txs: Vec<Transaction>) {
+        for tx in txs {
+            let _ = self.priv_transition(Transition::BchMempoolTx(tx)).await;
+        }
+    }
+
     pub async fn pub_transition(&mut self, transition: Transition) -> anyhow::Result<()> {
         match &transition {
             Transition::Msg0 { .. } => {}
@@ -401,33 +660,103 @@ impl Runner<'_> {
     }
 
     pub async fn priv_transition(&mut self, transition: Transition) -> anyhow::Result<()> {
-        let (new_state, actions, error) = self.inner.clone().transition(transition);
+        let (mut new_state, actions, error) = self.inner.clone().transition(transition);
         if let Some(err) = error {
+            if actions
+                .iter()
+                .any(|action| matches!(action, Action::SafeDelete))
+            {
+                self.inner.aborted = Some(err.to_string());
+            }
             bail!(err);
         }
 
         for action in actions {
             match action {
                 Action::LockXmr(amount, addr) => {
-                    let msg = format!("  Send {} to {}  ", amount, addr.to_string());
-                    println!("|{:=^width$}|", "", width = msg.len());
-                    println!("|{msg}|");
-                    println!("|{:=^width$}|", "", width = msg.len());
+                    let height = self.monerod.get_block_count().await?.get();
+                    new_state = new_state
+                        .transition(Transition::SetXmrRestoreHeight(height))
+                        .0;
+
+                    match self.xmr_wallet {
+                        Some(xmr_wallet) => {
+                            // On failure the state is not saved,
+                            // so the next scan of the swaplock retries it
+                            let lock_tx = xmr_wallet
+                                .transfer(self.monero_wallet, amount, addr)
+                                .await?;
+                            println!(
+                                "[{}]: Xmr locked. txid: {}, tx key: {}",
+                                self.trade_id, lock_tx.tx_hash, lock_tx.tx_key
+                            );
+                            new_state = new_state.transition(Transition::XmrLocked(lock_tx)).0;
+                        }
+                        None => {
+                            let msg = format!("  Send {} to {}  ", amount, addr.to_string());
+                            println!("|{:=^width$}|", "", width = msg.len());
+                            println!("|{msg}|");
+                            println!("|{:=^width$}|", "", width = msg.len());
+                        }
+                    }
+                }
+                Action::SweepXmr {
+                    keypair,
+                    restore_height,
+                    destination,
+                } => {
+                    let sweep = xmr::sweep_all(
+                        self.monero_wallet,
+                        format!("{}_spend", self.trade_id),
+                        self.inner.swap.xmr_network,
+                        keypair,
+                        restore_height,
+                        destination,
+                    )
+                    .await;
+
+                    // check_xmr will retry it
+                    match sweep {
+                        Ok(tx_hashes) => {
+                            new_state = new_state.transition(Transition::XmrSwept(tx_hashes)).0
+                        }
+                        Err(e) => println!("[{}]: Sweep failed: {e}", self.trade_id),
+                    }
                 }
                 Action::UnlockBchNormal => {
-                    let mut buffer = Vec::new();
-                    let transaction = new_state.get_unlock_normal_tx().unwrap();
-                    transaction.consensus_encode(&mut buffer).unwrap();
-                    let tx_hex: String = buffer.encode_hex();
+                    let Some(transaction) = new_state.get_unlock_normal_tx() else {
+                        continue;
+                    };
 
                     println!("Broadcasting tx. Expected txid: {}", transaction.txid());
-                    println!("Hex: {}", tx_hex);
-                    let transaction_resp = self
-                        .bch
-                        .send("blockchain.transaction.broadcast", json!([tx_hex]))
-                        .await
-                        .unwrap();
-                    dbg!(transaction_resp);
+                    let fee = new_state.swap.mining_fee;
+                    broadcaster::send(
+                        self.bch,
+                        &self.trade_id,
+                        &mut new_state.pending_txs,
+                        transaction,
+                        fee,
+                    )
+                    .await;
+                }
+                Action::UnlockBchPunish => {
+                    let Some(transaction) = new_state.get_refund_to_alice_tx() else {
+                        continue;
+                    };
+
+                    println!(
+                        "Broadcasting tx. Refund -> Alice Output: {}",
+                        transaction.txid()
+                    );
+                    let fee = new_state.swap.mining_fee;
+                    broadcaster::send(
+                        self.bch,
+                        &self.trade_id,
+                        &mut new_state.pending_txs,
+                        transaction,
+                        fee,
+                    )
+                    .await;
                 }
                 _ => {}
             }
@@ -0,0 +1,167 @@
+use bitcoincash::{consensus::serialize, Transaction, Txid};
+use serde::{Deserialize, Serialize};
+
+use super::TcpElectrum;
+use crate::{contract::timelock::Confirmation, utils::bch_transaction, wallet::BchWallet};
+
+/// Blocks a spend may wait in the mempool before a child pays for it
+const CPFP_AFTER_BLOCKS: u32 = 3;
+
+/// A contract spend or funding kept with the trade until it is mined
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingTx {
+    #[serde(with = "bch_transaction")]
+    pub transaction: Transaction,
+    /// Sats paid, fixed when signed
+    pub fee: u64,
+    pub attempts: u32,
+    /// Tip when the server first knew it
+    pub accepted_at: Option<u32>,
+    /// Why the last broadcast failed
+    pub last_error: Option<String>,
+    /// Child paying for it, see `BchWallet::pay_for`
+    pub cpfp: Option<Txid>,
+}
+
+impl PendingTx {
+    pub fn new(transaction: Transaction, fee: u64) -> Self {
+        PendingTx {
+            transaction,
+            fee,
+            attempts: 0,
+            accepted_at: None,
+            last_error: None,
+            cpfp: None,
+        }
+    }
+
+    /// Send it unless the server already has it
+    async fn broadcast(&mut self, bch: &TcpElectrum, trade_id: &str, tip: Option<u32>) {
+        let txid = self.transaction.txid();
+        if bch.get_transaction(&txid).await.is_ok() {
+            self.accepted_at = self.accepted_at.or(tip);
+            self.last_error = None;
+            return;
+        }
+
+        self.attempts += 1;
+        match bch.broadcast(&self.transaction).await {
+            Ok(txid) => {
+                println!("[{trade_id}]: Broadcasted {txid}");
+                self.accepted_at = self.accepted_at.or(tip);
+                self.last_error = None;
+            }
+            Err(e) => {
+                println!("[{trade_id}]: Broadcast of {txid} failed: {e}");
+                self.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+/// Queue `transaction` and send it. Queued txs are left to `rebroadcast`
+pub async fn send(
+    bch: &TcpElectrum,
+    trade_id: &str,
+    pending: &mut Vec<PendingTx>,
+    transaction: Transaction,
+    fee: u64,
+) {
+    let txid = transaction.txid();
+    if pending.iter().any(|tx| tx.transaction.txid() == txid) {
+        return;
+    }
+
+    let mut pending_tx = PendingTx::new(transaction, fee);
+    pending_tx.broadcast(bch, trade_id, None).await;
+    pending.push(pending_tx);
+}
+
+/// Drop the txs mined or replaced in `confirmed`, and send the others again.
+///
+/// The contracts fix the fee, so a tx stuck in the mempool can only be
+/// sped up by a child spending its output. `bch_recv` pays for it when it owns one
+pub async fn rebroadcast(
+    bch: &TcpElectrum,
+    trade_id: &str,
+    pending: &mut Vec<PendingTx>,
+    confirmed: &[(Transaction, Confirmation)],
+    bch_recv: &BchWallet,
+) {
+    pending.retain(|tx| {
+        let txid = tx.transaction.txid();
+        let spends_same = |other: &Transaction| {
+            other.input.iter().any(|input| {
+                tx.transaction
+                    .input
+                    .iter()
+                    .any(|ours| ours.previous_output == input.previous_output)
+            })
+        };
+
+        match confirmed.iter().find(|(other, _)| spends_same(other)) {
+            Some((other, _)) if other.txid() == txid => {
+                println!("[{trade_id}]: {txid} mined");
+                false
+            }
+            Some((other, _)) => {
+                println!("[{trade_id}]: {txid} replaced by {}", other.txid());
+                false
+            }
+            None => true,
+        }
+    });
+
+    let tip = confirmed
+        .iter()
+        .map(|(_, confirmation)| confirmation.tip)
+        .max();
+    for index in 0..pending.len() {
+        pending[index].broadcast(bch, trade_id, tip).await;
+
+        let stuck = match (tip, pending[index].accepted_at) {
+            (Some(tip), Some(accepted_at)) => tip >= accepted_at + CPFP_AFTER_BLOCKS,
+            _ => false,
+        };
+        let pays_us = pending[index]
+            .transaction
+            .output
+            .iter()
+            .any(|output| output.script_pubkey == bch_recv.script());
+        if !stuck || !pays_us || pending[index].cpfp.is_some() {
+            continue;
+        }
+
+        // its queued parents are unconfirmed too
+        let (parents, rest) = pending.split_at_mut(index);
+        let tx = &mut rest[0];
+        let mut size = serialize(&tx.transaction).len() as u64;
+        let mut fee = tx.fee;
+        for parent in parents.iter() {
+            let parent_txid = parent.transaction.txid();
+            if tx
+                .transaction
+                .input
+                .iter()
+                .any(|input| input.previous_output.txid == parent_txid)
+            {
+                size += serialize(&parent.transaction).len() as u64;
+                fee += parent.fee;
+            }
+        }
+
+        match bch_recv.pay_for(bch, &tx.transaction, size, fee).await {
+            Ok(child) => {
+                println!("[{trade_id}]: {child} pays for {}", tx.transaction.txid());
+                tx.cpfp = Some(child);
+            }
+            Err(e) => {
+                println!(
+                    "[{trade_id}]: Cannot pay for {}: {e}",
+                    tx.transaction.txid()
+                );
+                tx.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::fmt;
+
+use bitcoincash::{
+    consensus::{deserialize, serialize},
+    hashes::{sha256, Hash},
+    BlockHeader, Script, Transaction, TxMerkleNode, Txid,
+};
+use hex::ToHex;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+use super::{TcpElectrum, TcpElectrumError};
+
+#[derive(Debug)]
+pub enum ElectrumError {
+    Transport(TcpElectrumError),
+    /// JSON-RPC `error` object returned by the server
+    Rpc {
+        code: i64,
+        message: String,
+    },
+    InvalidResponse(String),
+    /// The server data does not match the local header chain
+    InvalidProof(String),
+}
+
+impl fmt::Display for ElectrumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "Transport {e}"),
+            Self::Rpc { code, message } => write!(f, "Rpc error {code}: {message}"),
+            Self::InvalidResponse(e) => write!(f, "InvalidResponse {e}"),
+            Self::InvalidProof(e) => write!(f, "InvalidProof {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ElectrumError {}
+
+impl From<TcpElectrumError> for ElectrumError {
+    fn from(value: TcpElectrumError) -> Self {
+        ElectrumError::Transport(value)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistoryItem {
+    pub tx_hash: Txid,
+    /// 0 if in mempool, -1 if in mempool with unconfirmed parents
+    pub height: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TxInfo {
+    pub transaction: Transaction,
+    /// 0 if not yet mined
+    pub confirmations: u32,
+}
+
+#[derive(Deserialize)]
+struct RawTxInfo {
+    #[serde(default)]
+    confirmations: u32,
+    #[serde(with = "hex")]
+    hex: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub height: u32,
+    pub header: BlockHeader,
+}
+
+#[derive(Deserialize)]
+struct RawHeader {
+    height: u32,
+    #[serde(with = "hex")]
+    hex: Vec<u8>,
+}
+
+impl TryFrom<RawHeader> for Header {
+    type Error = ElectrumError;
+
+    fn try_from(value: RawHeader) -> Result<Self, Self::Error> {
+        let header =
+            deserialize(&value.hex).map_err(|e| ElectrumError::InvalidResponse(e.to_string()))?;
+        Ok(Header {
+            height: value.height,
+            header,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct RawHeaders {
+    count: usize,
+    #[serde(with = "hex")]
+    hex: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MerkleProof {
+    pub block_height: u32,
+    pub merkle: Vec<TxMerkleNode>,
+    pub pos: usize,
+}
+
+/// Electrum identifies scripts by the reversed sha256 of the locking script
+pub fn script_hash(script: &Script) -> String {
+    let mut hash = sha256::Hash::hash(script.as_bytes()).into_inner();
+    hash.reverse();
+    hash.encode_hex()
+}
+
+/// Parse a JSON-RPC header notification, e.g. from `TcpElectrum::subscribe`
+pub fn parse_header_notification(params: Value) -> Result<Header, ElectrumError> {
+    let (raw,): (RawHeader,) = serde_json::from_value(params)
+        .map_err(|e| ElectrumError::InvalidResponse(e.to_string()))?;
+    raw.try_into()
+}
+
+fn parse_response<T: DeserializeOwned>(response: &str) -> Result<T, ElectrumError> {
+    let mut response = serde_json::from_str::<Value>(response)
+        .map_err(|e| ElectrumError::InvalidResponse(e.to_string()))?;
+
+    match response.get("error") {
+        None | Some(Value::Null) => {}
+        Some(error) => {
+            return Err(ElectrumError::Rpc {
+                code: error["code"].as_i64().unwrap_or_default(),
+                message: match error["message"].as_str() {
+                    Some(message) => message.to_owned(),
+                    None => error.to_string(),
+                },
+            })
+        }
+    }
+
+    serde_json::from_value(response["result"].take())
+        .map_err(|e| ElectrumError::InvalidResponse(e.to_string()))
+}
+
+impl TcpElectrum {
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<T, ElectrumError> {
+        let response = self.send(method, params).await?;
+        parse_response(&response)
+    }
+
+    pub async fn get_history(&self, address: &str) -> Result<Vec<HistoryItem>, ElectrumError> {
+        self.request("blockchain.address.get_history", json!([address, true]))
+            .await
+    }
+
+    pub async fn get_transaction(&self, txid: &Txid) -> Result<TxInfo, ElectrumError> {
+        let raw: RawTxInfo = self
+            .request("blockchain.transaction.get", json!([txid, true]))
+            .await?;
+
+        Ok(TxInfo {
+            transaction: deserialize(&raw.hex)
+                .map_err(|e| ElectrumError::InvalidResponse(e.to_string()))?,
+            confirmations: raw.confirmations,
+        })
+    }
+
+    pub async fn broadcast(&self, transaction: &Transaction) -> Result<Txid, ElectrumError> {
+        let tx_hex: String = serialize(transaction).encode_hex();
+        self.request("blockchain.transaction.broadcast", json!([tx_hex]))
+            .await
+    }
+
+    /// Returns the current tip. New tips are then notified through `subscribe`
+    pub async fn headers_subscribe(&self) -> Result<Header, ElectrumError> {
+        let raw: RawHeader = self
+            .request("blockchain.headers.subscribe", json!([]))
+            .await?;
+        raw.try_into()
+    }
+
+    /// Up to `count` consecutive headers from `start_height`, fewer past the tip
+    pub async fn block_headers(
+        &self,
+        start_height: u32,
+        count: u32,
+    ) -> Result<Vec<BlockHeader>, ElectrumError> {
+        let raw: RawHeaders = self
+            .request("blockchain.block.headers", json!([start_height, count]))
+            .await?;
+        if raw.hex.len() != raw.count * 80 {
+            return Err(ElectrumError::InvalidResponse(format!(
+                "Expected {} headers, got {} bytes",
+                raw.count,
+                raw.hex.len()
+            )));
+        }
+
+        raw.hex
+            .chunks(80)
+            .map(|header| {
+                deserialize(header).map_err(|e| ElectrumError::InvalidResponse(e.to_string()))
+            })
+            .collect()
+    }
+
+    /// Returns the current status of the script history, None if empty.
+    /// Changes are then notified through `subscribe`
+    pub async fn scripthash_subscribe(
+        &self,
+        script: &Script,
+    ) -> Result<Option<String>, ElectrumError> {
+        self.request(
+            "blockchain.scripthash.subscribe",
+            json!([script_hash(script)]),
+        )
+        .await
+    }
+
+    /// Stop the notifications, also after a reconnection
+    pub async fn scripthash_unsubscribe(&self, script: &Script) -> Result<bool, ElectrumError> {
+        let params = json!([script_hash(script)]);
+        self.subscriptions
+            .lock()
+            .await
+            .retain(|(method, p)| method != "blockchain.scripthash.subscribe" || *p != params);
+
+        self.request("blockchain.scripthash.unsubscribe", params)
+            .await
+    }
+
+    /// Fee rate in BCH per kilobyte, -1 if the server has no estimate
+    pub async fn estimate_fee(&self, blocks: u32) -> Result<f64, ElectrumError> {
+        self.request("blockchain.estimatefee", json!([blocks]))
+            .await
+    }
+
+    pub async fn get_merkle(&self, txid: &Txid, height: u32) -> Result<MerkleProof, ElectrumError> {
+        self.request("blockchain.transaction.get_merkle", json!([txid, height]))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_response, ElectrumError};
+
+    #[test]
+    fn should_return_rpc_error() {
+        let response = r#"{"id":1,"jsonrpc":"2.0","error":{"code":1,"message":"missing inputs"}}"#;
+        let result = parse_response::<String>(response);
+        assert!(matches!(
+            result,
+            Err(ElectrumError::Rpc { code: 1, ref message }) if message == "missing inputs"
+        ));
+
+        let response = r#"{"id":1,"jsonrpc":"2.0","result":"ok"}"#;
+        assert_eq!(parse_response::<String>(response).unwrap(), "ok");
+    }
+}
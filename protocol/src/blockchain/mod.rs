@@ -1,35 +1,60 @@
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use bitcoincash::Transaction;
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use tokio::{
-    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
     sync::{broadcast, oneshot, Mutex},
-    time::sleep,
+    time::{sleep, timeout},
 };
 
+pub mod broadcaster;
+pub mod electrum;
+pub mod spv;
+pub mod transport;
+pub mod watch;
+pub mod xmr;
+
+use transport::{ElectrumServer, Stream};
+
+/// Rough size in bytes of a tx spending one of the contracts
+const CONTRACT_SPEND_SIZE: u64 = 320;
+
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+/// The server answers the pings, so a silent connection is dead
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+type StreamRead = ReadHalf<Box<dyn Stream>>;
+type StreamWrite = WriteHalf<Box<dyn Stream>>;
+type Futures = Arc<Mutex<HashMap<u64, oneshot::Sender<Result<String, TcpElectrumError>>>>>;
+
 #[derive(Deserialize)]
 struct HasId {
     id: u64,
 }
 
 pub struct TcpElectrum {
-    futures: Arc<Mutex<HashMap<u64, oneshot::Sender<String>>>>,
+    futures: Futures,
     producer: broadcast::Sender<String>,
 
     id: Arc<Mutex<u64>>,
-    stream_write: Arc<Mutex<OwnedWriteHalf>>,
+    /// None while reconnecting
+    stream_write: Arc<Mutex<Option<StreamWrite>>>,
+    /// Replayed on every new connection
+    subscriptions: Arc<Mutex<Vec<(String, Value)>>>,
+    request_timeout: Duration,
 }
 
 #[derive(Debug)]
 pub enum TcpElectrumError {
     IoError(io::Error),
     RecvError(oneshot::error::RecvError),
+    /// The connection was lost before the response arrived
+    Disconnected,
+    Timeout,
 }
 
 impl std::fmt::Display for TcpElectrumError {
@@ -37,6 +62,8 @@ impl std::fmt::Display for TcpElectrumError {
         match self {
             Self::IoError(e) => write!(f, "IoError {e}"),
             Self::RecvError(e) => write!(f, "RecvError {e}"),
+            Self::Disconnected => write!(f, "Disconnected"),
+            Self::Timeout => write!(f, "Timeout"),
         }
     }
 }
@@ -44,53 +71,130 @@ impl std::fmt::Display for TcpElectrumError {
 impl std::error::Error for TcpElectrumError {}
 
 impl TcpElectrum {
-    pub fn new(stream: TcpStream) -> Self {
-        let (producer, _) = broadcast::channel(10);
-        let (stream_read, stream_write) = stream.into_split();
-
-        let id = Arc::new(Mutex::new(0));
-        let futures = Arc::new(Mutex::new(HashMap::new()));
-        let stream_write = Arc::new(Mutex::new(stream_write));
-
-        tokio::spawn({
-            let producer = producer.clone();
-            let futures = futures.clone();
-            async move {
-                let stream_read = BufReader::new(stream_read);
-                TcpElectrum::process_reads(stream_read, producer, futures).await;
-            }
-        });
+    /// Connect to the first reachable server of `servers`.
+    /// When the connection drops, the following servers are tried in turn
+    /// with an increasing backoff until one answers
+    pub async fn connect(
+        servers: Vec<ElectrumServer>,
+        request_timeout: Duration,
+    ) -> io::Result<Self> {
+        let (stream, index) = connect_any(&servers, 0).await?;
 
+        // one notification per watched script may arrive at once
+        let (producer, _) = broadcast::channel(256);
         let server = TcpElectrum {
-            id,
-            futures,
+            id: Arc::new(Mutex::new(0)),
+            futures: Arc::new(Mutex::new(HashMap::new())),
             producer,
-            stream_write,
+            stream_write: Arc::new(Mutex::new(None)),
+            subscriptions: Arc::new(Mutex::new(Vec::new())),
+            request_timeout,
         };
 
+        let (stream_read, stream_write) = io::split(stream);
+        *server.stream_write.lock().await = Some(stream_write);
+
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.run(servers, stream_read, index).await }
+        });
+
         tokio::spawn({
             let server = server.clone();
             async move {
                 loop {
                     let _ = server.send("server.ping", json!([])).await;
-                    sleep(Duration::from_secs(5)).await;
+                    sleep(PING_INTERVAL).await;
                 }
             }
         });
 
-        server
+        Ok(server)
+    }
+
+    async fn run(
+        self,
+        servers: Vec<ElectrumServer>,
+        mut stream_read: StreamRead,
+        mut index: usize,
+    ) {
+        loop {
+            let reader = BufReader::new(stream_read);
+            TcpElectrum::process_reads(reader, &self.producer, &self.futures).await;
+
+            *self.stream_write.lock().await = None;
+            for (_, pending) in self.futures.lock().await.drain() {
+                let _ = pending.send(Err(TcpElectrumError::Disconnected));
+            }
+            println!("Electrum server {} disconnected", servers[index]);
+
+            let mut backoff = MIN_BACKOFF;
+            let stream = loop {
+                match connect_any(&servers, index + 1).await {
+                    Ok((stream, connected)) => {
+                        index = connected;
+                        break stream;
+                    }
+                    Err(e) => {
+                        println!("Electrum reconnection failed: {e}");
+                        sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            };
+            println!("Reconnected to electrum server {}", servers[index]);
+
+            let (read, write) = io::split(stream);
+            stream_read = read;
+            *self.stream_write.lock().await = Some(write);
+            self.resubscribe().await;
+        }
+    }
+
+    /// Subscribe again on the new connection. The current status is forwarded
+    /// as a notification, so listeners catch up on what they missed
+    async fn resubscribe(&self) {
+        let subscriptions = self.subscriptions.lock().await.clone();
+        for (method, params) in subscriptions {
+            let server = self.clone();
+            tokio::spawn(async move {
+                let Ok(response) = server.send(&method, params.clone()).await else {
+                    return;
+                };
+                let Ok(mut response) = serde_json::from_str::<Value>(&response) else {
+                    return;
+                };
+
+                let mut params = match params {
+                    Value::Array(params) => params,
+                    _ => Vec::new(),
+                };
+                params.push(response["result"].take());
+
+                let notification = json!({"jsonrpc": "2.0", "method": method, "params": params});
+                let _ = server.producer.send(notification.to_string());
+            });
+        }
     }
 
     async fn process_reads(
-        mut reader: BufReader<OwnedReadHalf>,
-        producer: broadcast::Sender<String>,
-        futures: Arc<Mutex<HashMap<u64, oneshot::Sender<String>>>>,
+        mut reader: BufReader<StreamRead>,
+        producer: &broadcast::Sender<String>,
+        futures: &Futures,
     ) {
         loop {
             let mut buf = String::new();
-            let _ = reader.read_line(&mut buf).await.unwrap();
-            if buf == "" {
-                break;
+            match timeout(READ_TIMEOUT, reader.read_line(&mut buf)).await {
+                Ok(Ok(0)) => break,
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    println!("Electrum read failed: {e}");
+                    break;
+                }
+                Err(_) => {
+                    println!("Electrum server stopped answering");
+                    break;
+                }
             }
 
             match serde_json::from_str::<HasId>(&buf) {
@@ -99,7 +203,7 @@ impl TcpElectrum {
                 }
                 Ok(HasId { id }) => {
                     if let Some(recv) = futures.lock().await.remove(&id) {
-                        let _ = recv.send(buf);
+                        let _ = recv.send(Ok(buf));
                     }
                 }
             }
@@ -110,13 +214,19 @@ impl TcpElectrum {
         self.producer.subscribe()
     }
 
-    pub async fn send(
-        &self,
-        method: &str,
-        params: serde_json::Value,
-    ) -> Result<String, TcpElectrumError> {
+    pub async fn send(&self, method: &str, params: Value) -> Result<String, TcpElectrumError> {
+        if method.ends_with(".subscribe") {
+            let mut subscriptions = self.subscriptions.lock().await;
+            if !subscriptions
+                .iter()
+                .any(|(m, p)| m == method && *p == params)
+            {
+                subscriptions.push((method.to_owned(), params.clone()));
+            }
+        }
+
         let mut guard = self.id.lock().await;
-        let id = guard.clone();
+        let id = *guard;
         *guard += 1;
         drop(guard);
 
@@ -125,19 +235,27 @@ impl TcpElectrum {
         payload.push(b'\n');
 
         let (sender, recv) = oneshot::channel();
-        let mut guard = self.futures.lock().await;
-        let _ = guard.insert(id, sender);
-        drop(guard);
+        let _ = self.futures.lock().await.insert(id, sender);
 
-        let mut guard = self.stream_write.lock().await;
-        let _ = guard
-            .write(&payload)
-            .await
-            .map_err(|e| TcpElectrumError::IoError(e))?;
-        drop(guard);
+        let written = match self.stream_write.lock().await.as_mut() {
+            Some(stream_write) => stream_write
+                .write_all(&payload)
+                .await
+                .map_err(TcpElectrumError::IoError),
+            None => Err(TcpElectrumError::Disconnected),
+        };
+        if let Err(e) = written {
+            self.futures.lock().await.remove(&id);
+            return Err(e);
+        }
 
-        let result = recv.await.map_err(|e| TcpElectrumError::RecvError(e))?;
-        Ok(result)
+        match timeout(self.request_timeout, recv).await {
+            Ok(result) => result.map_err(TcpElectrumError::RecvError)?,
+            Err(_) => {
+                self.futures.lock().await.remove(&id);
+                Err(TcpElectrumError::Timeout)
+            }
+        }
     }
 }
 
@@ -148,60 +266,68 @@ impl Clone for TcpElectrum {
             futures: self.futures.clone(),
             producer: self.producer.clone(),
             stream_write: self.stream_write.clone(),
+            subscriptions: self.subscriptions.clone(),
+            request_timeout: self.request_timeout,
         }
     }
 }
 
-#[derive(Deserialize)]
-pub struct TxInfo0 {
-    confirmations: u32,
-    #[serde(with = "hex")]
-    hex: Vec<u8>,
+/// Try every server once, starting at `start`
+async fn connect_any(
+    servers: &[ElectrumServer],
+    start: usize,
+) -> io::Result<(Box<dyn Stream>, usize)> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "No electrum server");
+    for i in 0..servers.len() {
+        let index = (start + i) % servers.len();
+        match timeout(CONNECT_TIMEOUT, servers[index].connect()).await {
+            Ok(Ok(stream)) => return Ok((stream, index)),
+            Ok(Err(e)) => last_error = e,
+            Err(_) => {
+                last_error = io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Connection to {} timed out", servers[index]),
+                )
+            }
+        }
+    }
+
+    Err(last_error)
 }
 
-#[derive(Deserialize)]
-pub struct TxInfo {
-    result: TxInfo0,
+/// Sats per byte for a tx to confirm within `blocks`.
+/// Falls back to 1 sat/byte when the server has no estimate
+pub async fn estimate_fee_rate(bch_server: &TcpElectrum, blocks: u32) -> anyhow::Result<u64> {
+    Ok(fee_rate(bch_server.estimate_fee(blocks).await?))
 }
 
-pub async fn scan_address_conf_tx(
-    bch_server: &TcpElectrum,
-    address: &str,
-    min_conf: u32,
-) -> Vec<(Transaction, u32)> {
-    let response = bch_server
-        .send("blockchain.address.get_history", json!([address, true]))
-        .await
-        .unwrap();
-
-    let tx_hashes = serde_json::from_str::<serde_json::Value>(&response).unwrap()["result"]
-        .as_array()
-        .unwrap()
-        .to_owned();
-
-    let mut txs = Vec::new();
-    for tx in tx_hashes {
-        // in mempool
-        if tx["height"].as_u64().unwrap() == 0 {
-            continue;
-        }
+/// Mining fee in sats for a contract spend to confirm within `blocks`
+pub async fn estimate_mining_fee(bch_server: &TcpElectrum, blocks: u32) -> anyhow::Result<u64> {
+    Ok(mining_fee(bch_server.estimate_fee(blocks).await?))
+}
 
-        let tx_hash = tx["tx_hash"].as_str().unwrap();
-        let tx_info = bch_server
-            .send("blockchain.transaction.get", json!([tx_hash, true]))
-            .await
-            .unwrap();
+/// `bch_per_kb` as given by `blockchain.estimatefee`, -1 if unknown
+fn fee_rate(bch_per_kb: f64) -> u64 {
+    (bch_per_kb * 100_000.0).ceil().max(1.0) as u64
+}
 
-        let tx_info = serde_json::from_str::<TxInfo>(&tx_info).unwrap().result;
-        if tx_info.confirmations < min_conf {
-            continue;
-        }
+fn mining_fee(bch_per_kb: f64) -> u64 {
+    fee_rate(bch_per_kb) * CONTRACT_SPEND_SIZE
+}
 
-        txs.push((
-            bitcoincash::consensus::deserialize::<bitcoincash::Transaction>(&tx_info.hex).unwrap(),
-            tx_info.confirmations,
-        ));
-    }
+#[cfg(test)]
+mod test {
+    use super::{fee_rate, mining_fee, CONTRACT_SPEND_SIZE};
+
+    #[test]
+    fn should_estimate_mining_fee() {
+        assert_eq!(fee_rate(0.00001), 1);
+        assert_eq!(fee_rate(0.000012), 2);
+        // no estimate from the server
+        assert_eq!(fee_rate(-1.0), 1);
+        assert_eq!(fee_rate(0.0), 1);
 
-    txs
+        assert_eq!(mining_fee(-1.0), CONTRACT_SPEND_SIZE);
+        assert_eq!(mining_fee(0.0001), 10 * CONTRACT_SPEND_SIZE);
+    }
 }
@@ -0,0 +1,343 @@
+use bitcoincash::{
+    hashes::{sha256d, Hash, HashEngine},
+    BlockHeader, TxMerkleNode, Txid,
+};
+
+use super::{
+    electrum::{ElectrumError, Header, MerkleProof},
+    TcpElectrum,
+};
+use crate::{contract::timelock::Timelock, keys::bitcoin::Network};
+
+/// Most headers a server returns per request
+const MAX_HEADERS: u32 = 2016;
+
+/// Blocks in the median time past
+const MEDIAN_TIME_SPAN: u32 = 11;
+
+/// Deepest reorg followed. The chain is anchored this far below the first tip
+const MAX_REORG_DEPTH: u32 = 100;
+
+/// Easiest mainnet target accepted, far below the real difficulty
+/// so faking a header still costs a lot of work
+const MAINNET_MAX_TARGET_BITS: u32 = 0x1900ffff;
+
+/// Headers from an anchor to the tip, each with a valid proof of work
+/// and linked to the previous one.
+///
+/// The anchor, the first header synced, is trusted. Any tx accepted
+/// afterward must be in a block that builds on it.
+/// Blocks the server replaces are dropped, see `take_reorg`
+pub struct HeaderChain {
+    network: Network,
+    /// Height of `headers[0]`
+    start: u32,
+    headers: Vec<BlockHeader>,
+    /// Last common height of the reorgs since `take_reorg`
+    reorg: Option<u32>,
+}
+
+impl HeaderChain {
+    pub fn new(network: Network) -> Self {
+        HeaderChain {
+            network,
+            start: 0,
+            headers: Vec::new(),
+            reorg: None,
+        }
+    }
+
+    pub fn tip_height(&self) -> Option<u32> {
+        let len = self.headers.len() as u32;
+        (len > 0).then(|| self.start + len - 1)
+    }
+
+    pub fn header(&self, height: u32) -> Option<&BlockHeader> {
+        let index = height.checked_sub(self.start)?;
+        self.headers.get(index as usize)
+    }
+
+    /// Median time of the block at `height` and the ones before it, as BIP113
+    pub fn median_time_past(&self, height: u32) -> Option<u32> {
+        let mut times = (height.saturating_sub(MEDIAN_TIME_SPAN - 1)..=height)
+            .map(|height| self.header(height).map(|header| header.time))
+            .collect::<Option<Vec<u32>>>()?;
+        times.sort_unstable();
+
+        Some(times[times.len() / 2])
+    }
+
+    /// First height that can spend under `timelock` an output mined at `height`,
+    /// None while a time based lock is not reached by our tip
+    pub async fn unlock_height(
+        &mut self,
+        bch: &TcpElectrum,
+        timelock: Timelock,
+        height: u32,
+    ) -> Result<Option<u32>, ElectrumError> {
+        let Some(tip) = self.tip_height() else {
+            return Ok(None);
+        };
+        if let Timelock::Time(_) = timelock {
+            // the lock starts at the median time past of the parent block
+            self.sync_back(bch, height.saturating_sub(MEDIAN_TIME_SPAN))
+                .await?;
+        }
+
+        Ok(timelock.unlock_height(height, tip, |height| self.median_time_past(height)))
+    }
+
+    /// Returns the last height both chains shared, if blocks were replaced
+    pub fn take_reorg(&mut self) -> Option<u32> {
+        self.reorg.take()
+    }
+
+    /// Follow the new tip notified by the server
+    pub async fn sync(&mut self, bch: &TcpElectrum, tip: &Header) -> Result<(), ElectrumError> {
+        match self.header(tip.height) {
+            Some(header) if *header == tip.header => return Ok(()),
+            // our block at that height was replaced
+            Some(_) => self.rollback(bch).await?,
+            None => {}
+        }
+
+        self.sync_to(bch, tip.height).await
+    }
+
+    /// Validate and append the headers up to `height`
+    async fn sync_to(&mut self, bch: &TcpElectrum, height: u32) -> Result<(), ElectrumError> {
+        if self.headers.is_empty() {
+            let start = height.saturating_sub(MAX_REORG_DEPTH);
+            let anchor = fetch_headers(bch, start, 1).await?.remove(0);
+            self.check_pow(&anchor, start)?;
+            self.start = start;
+            self.headers = vec![anchor];
+        }
+
+        let mut next = self.tip_height().unwrap() + 1;
+        'sync: while next <= height {
+            let count = (height - next + 1).min(MAX_HEADERS);
+            for header in fetch_headers(bch, next, count).await? {
+                let parent = self.headers.last().unwrap();
+                if header.prev_blockhash != parent.block_hash() {
+                    self.rollback(bch).await?;
+                    next = self.tip_height().unwrap() + 1;
+                    continue 'sync;
+                }
+
+                self.check(parent, &header, next)?;
+                self.headers.push(header);
+                next += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drop our blocks down to the last one the server still has
+    async fn rollback(&mut self, bch: &TcpElectrum) -> Result<(), ElectrumError> {
+        let tip = self.tip_height().unwrap();
+        let lowest = tip.saturating_sub(MAX_REORG_DEPTH).max(self.start);
+
+        let mut height = tip;
+        loop {
+            let theirs = bch.block_headers(height, 1).await?;
+            if theirs.first() == self.header(height) {
+                break;
+            }
+            if height == lowest {
+                return Err(ElectrumError::InvalidProof(format!(
+                    "Reorg deeper than {} blocks from {tip}",
+                    tip - lowest
+                )));
+            }
+            height -= 1;
+        }
+
+        eprintln!("Reorg: blocks above {height} were replaced");
+        self.headers.truncate((height - self.start + 1) as usize);
+        self.reorg = Some(self.reorg.map_or(height, |reorg| reorg.min(height)));
+
+        Ok(())
+    }
+
+    /// Prepend the headers down to `height`, each must be the parent of the next one
+    async fn sync_back(&mut self, bch: &TcpElectrum, height: u32) -> Result<(), ElectrumError> {
+        while height < self.start {
+            let from = height.max(self.start.saturating_sub(MAX_HEADERS));
+            let mut headers = fetch_headers(bch, from, self.start - from).await?;
+
+            let mut child = self.headers[0];
+            for (index, header) in headers.iter().enumerate().rev() {
+                self.check(header, &child, from + index as u32 + 1)?;
+                child = *header;
+            }
+            self.check_pow(&headers[0], from)?;
+
+            headers.append(&mut self.headers);
+            self.headers = headers;
+            self.start = from;
+        }
+
+        Ok(())
+    }
+
+    fn check(
+        &self,
+        parent: &BlockHeader,
+        header: &BlockHeader,
+        height: u32,
+    ) -> Result<(), ElectrumError> {
+        if header.prev_blockhash != parent.block_hash() {
+            return Err(ElectrumError::InvalidProof(format!(
+                "Header {height} does not extend the chain"
+            )));
+        }
+        self.check_pow(header, height)?;
+
+        // testnets may drop to the minimum difficulty, mainnet moves slowly
+        if let Network::Mainnet = self.network {
+            let (target, parent_target) = (header.target(), parent.target());
+            if target > parent_target << 1 || target < parent_target >> 1 {
+                return Err(ElectrumError::InvalidProof(format!(
+                    "Unexpected difficulty change at {height}"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_pow(&self, header: &BlockHeader, height: u32) -> Result<(), ElectrumError> {
+        let too_easy = match self.network {
+            Network::Mainnet => {
+                header.target() > BlockHeader::u256_from_compact_target(MAINNET_MAX_TARGET_BITS)
+            }
+            _ => false,
+        };
+
+        if too_easy || header.validate_pow(&header.target()).is_err() {
+            return Err(ElectrumError::InvalidProof(format!(
+                "Invalid proof of work at {height}"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Check that `txid` is in the block at `height` of our chain
+    pub async fn verify_tx(
+        &mut self,
+        bch: &TcpElectrum,
+        txid: &Txid,
+        height: u32,
+    ) -> Result<(), ElectrumError> {
+        if self.tip_height().is_none_or(|tip| height > tip) {
+            self.sync_to(bch, height).await?;
+        }
+        if height < self.start {
+            self.sync_back(bch, height).await?;
+        }
+
+        let proof = bch.get_merkle(txid, height).await?;
+        let header = self.header(height).expect("Header should be synced");
+        if proof.block_height != height || merkle_root(txid, &proof) != header.merkle_root {
+            return Err(ElectrumError::InvalidProof(format!(
+                "Tx {txid} is not in block {height}"
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+async fn fetch_headers(
+    bch: &TcpElectrum,
+    start_height: u32,
+    count: u32,
+) -> Result<Vec<BlockHeader>, ElectrumError> {
+    let headers = bch.block_headers(start_height, count).await?;
+    if headers.len() as u32 != count {
+        return Err(ElectrumError::InvalidResponse(format!(
+            "Missing headers from {start_height}"
+        )));
+    }
+
+    Ok(headers)
+}
+
+/// Root of the block merkle tree, from the branch of `txid`
+pub fn merkle_root(txid: &Txid, proof: &MerkleProof) -> TxMerkleNode {
+    let mut current = txid.into_inner();
+    for (depth, node) in proof.merkle.iter().enumerate() {
+        let mut engine = sha256d::Hash::engine();
+        if (proof.pos >> depth) & 1 == 1 {
+            engine.input(&node.into_inner());
+            engine.input(&current);
+        } else {
+            engine.input(&current);
+            engine.input(&node.into_inner());
+        }
+        current = sha256d::Hash::from_engine(engine).into_inner();
+    }
+
+    TxMerkleNode::from_inner(current)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoincash::{
+        hashes::{sha256d, Hash},
+        BlockHash, BlockHeader, TxMerkleNode, Txid,
+    };
+
+    use super::{merkle_root, HeaderChain};
+    use crate::{blockchain::electrum::MerkleProof, keys::bitcoin::Network};
+
+    fn mine(prev_blockhash: BlockHash) -> BlockHeader {
+        let mut header = BlockHeader {
+            version: 1,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: 0x207fffff,
+            nonce: 0,
+        };
+        while header.validate_pow(&header.target()).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    #[test]
+    fn should_compute_merkle_root() {
+        let a = Txid::hash(b"a");
+        let b = Txid::hash(b"b");
+        let root = sha256d::Hash::hash(&[a.into_inner(), b.into_inner()].concat());
+
+        let proof = MerkleProof {
+            block_height: 1,
+            merkle: vec![TxMerkleNode::from_inner(b.into_inner())],
+            pos: 0,
+        };
+        assert_eq!(merkle_root(&a, &proof).into_inner(), root.into_inner());
+
+        let proof = MerkleProof {
+            block_height: 1,
+            merkle: vec![TxMerkleNode::from_inner(a.into_inner())],
+            pos: 1,
+        };
+        assert_eq!(merkle_root(&b, &proof).into_inner(), root.into_inner());
+    }
+
+    #[test]
+    fn should_reject_unlinked_header() {
+        let chain = HeaderChain::new(Network::Regtest);
+        let parent = mine(BlockHash::all_zeros());
+
+        assert!(chain.check(&parent, &mine(parent.block_hash()), 1).is_ok());
+        assert!(chain
+            .check(&parent, &mine(BlockHash::all_zeros()), 1)
+            .is_err());
+    }
+}
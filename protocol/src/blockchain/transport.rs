@@ -0,0 +1,144 @@
+use std::{fmt, str::FromStr};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+/// Any byte stream the electrum protocol can run over
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Ssl {
+        /// Sha256 of the DER certificate. When set, it replaces the CA check
+        /// so self-signed servers can be used
+        fingerprint: Option<[u8; 32]>,
+    },
+}
+
+/// `host:port` for plaintext, `ssl://host:port` for TLS,
+/// `ssl://host:port#<sha256 hex>` to pin the server certificate
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElectrumServer {
+    pub host: String,
+    pub port: u16,
+    pub transport: Transport,
+}
+
+impl FromStr for ElectrumServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, transport) = match s.strip_prefix("ssl://") {
+            Some(rest) => {
+                let (addr, fingerprint) = match rest.split_once('#') {
+                    None => (rest, None),
+                    Some((addr, fingerprint)) => {
+                        let mut pin = [0u8; 32];
+                        hex::decode_to_slice(fingerprint.replace(':', ""), &mut pin)
+                            .map_err(|_| format!("Invalid certificate fingerprint in {s}"))?;
+                        (addr, Some(pin))
+                    }
+                };
+                (addr, Transport::Ssl { fingerprint })
+            }
+            None => (s.strip_prefix("tcp://").unwrap_or(s), Transport::Tcp),
+        };
+
+        let (host, port) = addr
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Missing port in {s}"))?;
+        let port = port.parse().map_err(|_| format!("Invalid port in {s}"))?;
+
+        Ok(ElectrumServer {
+            host: host.to_owned(),
+            port,
+            transport,
+        })
+    }
+}
+
+impl fmt::Display for ElectrumServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.transport {
+            Transport::Tcp => write!(f, "{}:{}", self.host, self.port),
+            Transport::Ssl { .. } => write!(f, "ssl://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl ElectrumServer {
+    pub async fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        let stream = TcpStream::connect((self.host.trim_matches(['[', ']']), self.port)).await?;
+
+        let fingerprint = match self.transport {
+            Transport::Tcp => return Ok(Box::new(stream)),
+            Transport::Ssl { fingerprint } => fingerprint,
+        };
+
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(fingerprint.is_some())
+            .danger_accept_invalid_hostnames(fingerprint.is_some())
+            .build()
+            .map_err(tls_error)?;
+        let stream = TlsConnector::from(connector)
+            .connect(&self.host, stream)
+            .await
+            .map_err(tls_error)?;
+
+        if let Some(fingerprint) = fingerprint {
+            let certificate = stream
+                .get_ref()
+                .peer_certificate()
+                .map_err(tls_error)?
+                .ok_or_else(|| tls_error("No certificate"))?;
+            let der = certificate.to_der().map_err(tls_error)?;
+
+            if Sha256::digest(der).as_slice() != fingerprint {
+                return Err(tls_error(format!("Certificate mismatch for {self}")));
+            }
+        }
+
+        Ok(Box::new(stream))
+    }
+}
+
+fn tls_error(e: impl ToString) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ElectrumServer, Transport};
+
+    #[test]
+    fn should_parse_server() {
+        let server: ElectrumServer = "localhost:50001".parse().unwrap();
+        assert_eq!(server.transport, Transport::Tcp);
+        assert_eq!(server.to_string(), "localhost:50001");
+
+        let pin = "ab".repeat(32);
+        let server: ElectrumServer = format!("ssl://electrum.example:50002#{pin}")
+            .parse()
+            .unwrap();
+        assert_eq!(server.host, "electrum.example");
+        assert_eq!(server.port, 50002);
+        assert_eq!(
+            server.transport,
+            Transport::Ssl {
+                fingerprint: Some([0xab; 32])
+            }
+        );
+
+        assert!("ssl://electrum.example".parse::<ElectrumServer>().is_err());
+        assert!("ssl://electrum.example:50002#abcd"
+            .parse::<ElectrumServer>()
+            .is_err());
+    }
+}
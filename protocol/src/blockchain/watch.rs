@@ -0,0 +1,489 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use bitcoincash::{Script, Transaction, Txid};
+use serde_json::Value;
+
+use super::{
+    electrum::{parse_header_notification, script_hash, ElectrumError, Header},
+    spv::HeaderChain,
+    TcpElectrum,
+};
+use crate::{
+    contract::{
+        timelock::{Confirmation, Timelock},
+        ContractPair,
+    },
+    keys::bitcoin::Network,
+};
+
+struct Watched {
+    trade_id: String,
+    /// 0 for the swaplock, 1 for the refund, so txs are replayed in order
+    index: usize,
+    script: Script,
+    address: String,
+    /// Electrum status of the history, None if empty
+    status: Option<String>,
+    /// Txs in history order with their height, 0 or less if in mempool.
+    /// Mined txs are proven to be in our header chain
+    history: Vec<(Txid, i64)>,
+    /// Refetch the history even if the status is the same, e.g. after a reorg
+    stale: bool,
+    timelock: Option<Timelock>,
+    /// Height from which each proven tx paying to the script can be spent
+    /// by the timelock branch
+    unlocks: HashMap<Txid, u32>,
+}
+
+#[derive(Default)]
+struct Inner {
+    tip: u32,
+    /// By script hash
+    scripts: HashMap<String, Watched>,
+    transactions: HashMap<Txid, Transaction>,
+    /// Height at which each tx inclusion was proven
+    verified: HashMap<Txid, i64>,
+}
+
+/// Follows the contracts of ongoing trades with scripthash subscriptions.
+///
+/// A history is fetched only when its status changes, and each tx once.
+/// New blocks only update the confirmations of the known txs
+pub struct ScriptWatcher {
+    inner: Mutex<Inner>,
+    chain: tokio::sync::Mutex<HeaderChain>,
+}
+
+impl ScriptWatcher {
+    pub fn new(network: Network) -> Self {
+        ScriptWatcher {
+            inner: Mutex::default(),
+            chain: tokio::sync::Mutex::new(HeaderChain::new(network)),
+        }
+    }
+
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+
+    /// Validate the headers up to the new tip
+    pub async fn sync_tip(&self, bch: &TcpElectrum, tip: &Header) -> Result<(), ElectrumError> {
+        {
+            let mut chain = self.chain.lock().await;
+            chain.sync(bch, tip).await?;
+            self.inner().tip = chain.tip_height().unwrap_or(tip.height);
+        }
+
+        // time based locks move with the chain
+        self.update_unlocks(bch).await
+    }
+
+    /// Height of the block holding `txid`, once proven
+    pub fn mined_height(&self, txid: &Txid) -> Option<u32> {
+        self.inner().verified.get(txid).map(|height| *height as u32)
+    }
+
+    /// Subscribe to the swaplock and refund of the trade, if not done yet
+    pub async fn watch(
+        &self,
+        bch: &TcpElectrum,
+        trade_id: &str,
+        contract_pair: &ContractPair,
+    ) -> Result<(), ElectrumError> {
+        let contracts = [&contract_pair.swaplock, &contract_pair.refund];
+        for (index, contract) in contracts.into_iter().enumerate() {
+            let script = Script::from(contract.locking_script());
+            let hash = script_hash(&script);
+            if self.inner().scripts.contains_key(&hash) {
+                continue;
+            }
+
+            let status = bch.scripthash_subscribe(&script).await?;
+            self.inner().scripts.insert(
+                hash.clone(),
+                Watched {
+                    trade_id: trade_id.to_owned(),
+                    index,
+                    script,
+                    address: contract.cash_address(),
+                    status: None,
+                    history: Vec::new(),
+                    stale: false,
+                    timelock: contract.relative_timelock(),
+                    unlocks: HashMap::new(),
+                },
+            );
+            self.update(bch, &hash, status).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn unwatch(&self, bch: &TcpElectrum, trade_id: &str) -> Result<(), ElectrumError> {
+        let scripts: Vec<Script> = {
+            let mut inner = self.inner();
+            let Inner {
+                scripts,
+                transactions,
+                verified,
+                ..
+            } = &mut *inner;
+
+            let removed = scripts
+                .iter()
+                .filter(|(_, watched)| watched.trade_id == trade_id)
+                .map(|(hash, _)| hash.clone())
+                .collect::<Vec<_>>();
+            let removed = removed
+                .iter()
+                .filter_map(|hash| scripts.remove(hash))
+                .map(|watched| watched.script)
+                .collect();
+
+            let known = |txid: &Txid| {
+                scripts
+                    .values()
+                    .any(|watched| watched.history.iter().any(|(id, _)| id == txid))
+            };
+            transactions.retain(|txid, _| known(txid));
+            verified.retain(|txid, _| known(txid));
+            removed
+        };
+
+        for script in scripts {
+            bch.scripthash_unsubscribe(&script).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Handle a notification from `TcpElectrum::subscribe`.
+    /// Returns the trades whose txs changed
+    pub async fn on_notification(
+        &self,
+        bch: &TcpElectrum,
+        notification: &str,
+    ) -> Result<Vec<String>, ElectrumError> {
+        let mut notification = serde_json::from_str::<Value>(notification)
+            .map_err(|e| ElectrumError::InvalidResponse(e.to_string()))?;
+        let params = notification["params"].take();
+
+        let mut trade_ids = match notification["method"].as_str() {
+            Some("blockchain.headers.subscribe") => {
+                let header = parse_header_notification(params)?;
+                self.sync_tip(bch, &header).await?;
+
+                // every mined tx got one more confirmation
+                self.inner()
+                    .scripts
+                    .values()
+                    .filter(|watched| watched.history.iter().any(|(_, height)| *height > 0))
+                    .map(|watched| watched.trade_id.clone())
+                    .collect()
+            }
+            Some("blockchain.scripthash.subscribe") => {
+                let (hash, status): (String, Option<String>) = serde_json::from_value(params)
+                    .map_err(|e| ElectrumError::InvalidResponse(e.to_string()))?;
+                self.update(bch, &hash, status).await?
+            }
+            _ => Vec::new(),
+        };
+
+        let reorg = self.chain.lock().await.take_reorg();
+        if let Some(fork) = reorg {
+            trade_ids.extend(self.rewind(bch, fork).await?);
+        }
+        trade_ids.sort();
+        trade_ids.dedup();
+
+        Ok(trade_ids)
+    }
+
+    /// Forget the proofs of txs mined above `fork` and refetch their histories
+    async fn rewind(&self, bch: &TcpElectrum, fork: u32) -> Result<Vec<String>, ElectrumError> {
+        {
+            let mut inner = self.inner();
+            let Inner {
+                scripts, verified, ..
+            } = &mut *inner;
+
+            verified.retain(|_, height| *height <= fork as i64);
+            for watched in scripts.values_mut() {
+                watched.unlocks.retain(|_, height| *height <= fork);
+                if watched
+                    .history
+                    .iter()
+                    .any(|(_, height)| *height > fork as i64)
+                {
+                    watched.stale = true;
+                }
+            }
+        }
+
+        let trade_ids = self.resubscribe(bch, |watched| watched.stale).await?;
+        eprintln!("Reorg above {fork}, trades affected: {trade_ids:?}");
+
+        Ok(trade_ids)
+    }
+
+    /// Ask every status again, e.g. after missing notifications.
+    /// Returns the trades whose txs changed
+    pub async fn refresh(&self, bch: &TcpElectrum) -> Result<Vec<String>, ElectrumError> {
+        self.resubscribe(bch, |_| true).await
+    }
+
+    async fn resubscribe(
+        &self,
+        bch: &TcpElectrum,
+        filter: impl Fn(&Watched) -> bool,
+    ) -> Result<Vec<String>, ElectrumError> {
+        let scripts: Vec<(String, Script)> = self
+            .inner()
+            .scripts
+            .iter()
+            .filter(|(_, watched)| filter(watched))
+            .map(|(hash, watched)| (hash.clone(), watched.script.clone()))
+            .collect();
+
+        let mut trade_ids = Vec::new();
+        for (hash, script) in scripts {
+            let status = bch.scripthash_subscribe(&script).await?;
+            trade_ids.extend(self.update(bch, &hash, status).await?);
+        }
+        trade_ids.sort();
+        trade_ids.dedup();
+
+        Ok(trade_ids)
+    }
+
+    /// Refetch the history if its status changed, keeping the mined txs
+    /// we can prove. The status is kept if a tx fails its proof, so the next notification retries
+    async fn update(
+        &self,
+        bch: &TcpElectrum,
+        hash: &str,
+        status: Option<String>,
+    ) -> Result<Vec<String>, ElectrumError> {
+        let (trade_id, address) = match self.inner().scripts.get(hash) {
+            Some(watched) if watched.stale || watched.status != status => {
+                (watched.trade_id.clone(), watched.address.clone())
+            }
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut history = match status {
+            Some(_) => bch.get_history(&address).await?,
+            None => Vec::new(),
+        };
+
+        for item in history.iter() {
+            if self.inner().transactions.contains_key(&item.tx_hash) {
+                continue;
+            }
+
+            let tx_info = bch.get_transaction(&item.tx_hash).await?;
+            if tx_info.transaction.txid() != item.tx_hash {
+                return Err(ElectrumError::InvalidProof(format!(
+                    "Server returned another tx for {}",
+                    item.tx_hash
+                )));
+            }
+            self.inner()
+                .transactions
+                .insert(item.tx_hash, tx_info.transaction);
+        }
+
+        let mut proven = Vec::with_capacity(history.len());
+        for item in history.iter() {
+            let verified = self.inner().verified.get(&item.tx_hash) == Some(&item.height);
+            if item.height <= 0 || verified {
+                proven.push(true);
+                continue;
+            }
+
+            let mut chain = self.chain.lock().await;
+            match chain
+                .verify_tx(bch, &item.tx_hash, item.height as u32)
+                .await
+            {
+                Ok(()) => {
+                    self.inner().verified.insert(item.tx_hash, item.height);
+                    proven.push(true);
+                }
+                Err(ElectrumError::InvalidProof(e)) => {
+                    eprintln!("[{trade_id}]: Ignoring unproven tx {}: {e}", item.tx_hash);
+                    proven.push(false);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let complete = proven.iter().all(|proven| *proven);
+        let mut proven = proven.into_iter();
+        history.retain(|_| proven.next().unwrap());
+
+        {
+            let mut inner = self.inner();
+            let Some(watched) = inner.scripts.get_mut(hash) else {
+                return Ok(Vec::new());
+            };
+            // an unproven tx leaves the old status, the next notification refetches
+            if complete {
+                watched.status = status;
+            }
+            watched.stale = !complete;
+            watched.history = history
+                .into_iter()
+                .map(|item| (item.tx_hash, item.height))
+                .collect();
+            watched.unlocks.clear();
+        }
+
+        self.update_unlocks(bch).await?;
+        Ok(vec![trade_id])
+    }
+
+    /// Find the unlock height of the proven txs paying to a contract
+    async fn update_unlocks(&self, bch: &TcpElectrum) -> Result<(), ElectrumError> {
+        let pending: Vec<(String, Timelock, Txid, u32)> = {
+            let inner = self.inner();
+            let mut pending = Vec::new();
+            for (hash, watched) in inner.scripts.iter() {
+                let Some(timelock) = watched.timelock else {
+                    continue;
+                };
+
+                for (txid, height) in watched.history.iter() {
+                    let pays_to_script = inner.transactions.get(txid).is_some_and(|tx| {
+                        tx.output
+                            .iter()
+                            .any(|output| output.script_pubkey == watched.script)
+                    });
+                    if *height > 0 && pays_to_script && !watched.unlocks.contains_key(txid) {
+                        pending.push((hash.clone(), timelock, *txid, *height as u32));
+                    }
+                }
+            }
+            pending
+        };
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut chain = self.chain.lock().await;
+        for (hash, timelock, txid, height) in pending {
+            let Some(unlock_height) = chain.unlock_height(bch, timelock, height).await? else {
+                continue;
+            };
+            if let Some(watched) = self.inner().scripts.get_mut(&hash) {
+                watched.unlocks.insert(txid, unlock_height);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mined txs of the trade with their confirmations, same as `scan_address_conf_tx`
+    pub fn confirmed_txs(&self, trade_id: &str, min_conf: u32) -> Vec<(Transaction, Confirmation)> {
+        let inner = self.inner();
+
+        let mut scripts: Vec<&Watched> = inner
+            .scripts
+            .values()
+            .filter(|watched| watched.trade_id == trade_id)
+            .collect();
+        scripts.sort_by_key(|watched| watched.index);
+
+        let mut txs = Vec::new();
+        for watched in scripts {
+            for (txid, height) in watched.history.iter() {
+                // in mempool
+                if *height <= 0 {
+                    continue;
+                }
+
+                let confirmation = Confirmation {
+                    height: *height as u32,
+                    tip: inner.tip,
+                    unlock_height: watched.unlocks.get(txid).copied(),
+                };
+                if confirmation.confirmations() < min_conf {
+                    continue;
+                }
+
+                if let Some(transaction) = inner.transactions.get(txid) {
+                    txs.push((transaction.clone(), confirmation));
+                }
+            }
+        }
+
+        txs
+    }
+
+    /// Unconfirmed txs of the trade. They have no proof, so only
+    /// use them for what they carry, e.g. a signature
+    pub fn mempool_txs(&self, trade_id: &str) -> Vec<Transaction> {
+        let inner = self.inner();
+
+        let mut scripts: Vec<&Watched> = inner
+            .scripts
+            .values()
+            .filter(|watched| watched.trade_id == trade_id)
+            .collect();
+        scripts.sort_by_key(|watched| watched.index);
+
+        scripts
+            .iter()
+            .flat_map(|watched| watched.history.iter())
+            .filter(|(_, height)| *height <= 0)
+            .filter_map(|(txid, _)| inner.transactions.get(txid).cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoincash::{consensus::deserialize, Script, Transaction, Txid};
+
+    use super::{ScriptWatcher, Watched};
+    use crate::keys::bitcoin::Network;
+
+    #[test]
+    fn should_count_confirmations_from_tip() {
+        // a coinbase tx, only used as a cached tx
+        let tx: Transaction = deserialize(&hex::decode("01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d0104ffffffff0100f2052a0100000043410496b538e853519c726a2c91e61ec11600ae1390813a627c66fb8be7947be63c52da7589379515d4e0a604f8141781e62294721166bf621e73a82cbf2342c858eeac00000000").unwrap()).unwrap();
+        let txid: Txid = tx.txid();
+
+        let watcher = ScriptWatcher::new(Network::Regtest);
+        {
+            let mut inner = watcher.inner();
+            inner.tip = 110;
+            inner.transactions.insert(txid, tx);
+            inner.scripts.insert(
+                "hash".to_owned(),
+                Watched {
+                    trade_id: "trade".to_owned(),
+                    index: 0,
+                    script: Script::new(),
+                    address: "".to_owned(),
+                    status: Some("status".to_owned()),
+                    history: vec![(txid, 101)],
+                    stale: false,
+                    timelock: None,
+                    unlocks: [(txid, 120)].into(),
+                },
+            );
+        }
+
+        let confirmation = watcher.confirmed_txs("trade", 10)[0].1;
+        assert_eq!(confirmation.confirmations(), 10);
+        assert!(!confirmation.is_unlocked());
+        assert!(watcher.confirmed_txs("trade", 11).is_empty());
+        assert!(watcher.confirmed_txs("other", 1).is_empty());
+
+        watcher.inner().tip = 111;
+        assert_eq!(watcher.confirmed_txs("trade", 11)[0].1.confirmations(), 11);
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use monero_rpc::{
+    GenerateFromKeysArgs, SweepAllArgs, TransferOptions, TransferPriority, WalletClient,
+};
+use tokio::sync::Mutex;
+
+use crate::protocol::XmrLockTx;
+
+/// Existing monero-wallet-rpc wallet used to lock the xmr
+pub struct XmrWallet {
+    pub filename: String,
+    pub password: String,
+}
+
+impl XmrWallet {
+    pub fn new(filename: String, password: String) -> Self {
+        XmrWallet { filename, password }
+    }
+
+    /// Send `amount` to `destination` and return the proof of payment
+    pub async fn transfer(
+        &self,
+        monero_wallet: &Mutex<WalletClient>,
+        amount: monero::Amount,
+        destination: monero::Address,
+    ) -> anyhow::Result<XmrLockTx> {
+        let monero_wallet = monero_wallet.lock().await;
+        monero_wallet
+            .open_wallet(self.filename.clone(), Some(self.password.clone()))
+            .await?;
+
+        let transfer = async {
+            monero_wallet.refresh(None).await?;
+            monero_wallet
+                .transfer(
+                    HashMap::from([(destination, amount)]),
+                    TransferPriority::Default,
+                    TransferOptions::default(),
+                )
+                .await
+        }
+        .await;
+        monero_wallet.close_wallet().await?;
+
+        let transfer = transfer?;
+        Ok(XmrLockTx {
+            tx_hash: transfer.tx_hash.to_string(),
+            tx_key: transfer.tx_key.to_string(),
+        })
+    }
+}
+
+/// Restore a spend-capable wallet from `keypair` and send
+/// all unlocked balance to `destination`.
+///
+/// The wallet is created once under `filename`, then reopened on retry.
+/// Returns the hashes of the sweep transactions
+pub async fn sweep_all(
+    monero_wallet: &Mutex<WalletClient>,
+    filename: String,
+    network: monero::Network,
+    keypair: monero::KeyPair,
+    restore_height: u64,
+    destination: monero::Address,
+) -> anyhow::Result<Vec<String>> {
+    let monero_wallet = monero_wallet.lock().await;
+
+    if monero_wallet
+        .open_wallet(filename.clone(), Some("".to_owned()))
+        .await
+        .is_err()
+    {
+        monero_wallet
+            .generate_from_keys(GenerateFromKeysArgs {
+                address: monero::Address::from_keypair(network, &keypair),
+                restore_height: Some(restore_height),
+                autosave_current: Some(true),
+                filename,
+                password: "".to_owned(),
+                spendkey: Some(keypair.spend),
+                viewkey: keypair.view,
+            })
+            .await?;
+    }
+
+    let sweep = async {
+        monero_wallet.refresh(Some(restore_height)).await?;
+        monero_wallet
+            .sweep_all(SweepAllArgs {
+                address: destination,
+                account_index: 0,
+                subaddr_indices: None,
+                priority: TransferPriority::Default,
+                mixin: 15,
+                ring_size: 16,
+                unlock_time: 0,
+                get_tx_keys: None,
+                below_amount: None,
+                do_not_relay: None,
+                get_tx_hex: None,
+                get_tx_metadata: None,
+            })
+            .await
+    }
+    .await;
+    monero_wallet.close_wallet().await?;
+
+    let tx_hashes = sweep?
+        .tx_hash_list
+        .into_iter()
+        .map(|hash| hash.to_string())
+        .collect();
+    Ok(tx_hashes)
+}
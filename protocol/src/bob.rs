@@ -1,25 +1,25 @@
-use std::{fmt, time::Duration};
+use std::fmt;
 
 use anyhow::bail;
 use bitcoin_hashes::{sha256::Hash as sha256, Hash};
-use bitcoincash::{
-    consensus::Encodable, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut,
-};
+use bitcoincash::{PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut};
 use ecdsa_fun::adaptor::EncryptedSignature;
-use hex::ToHex;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tokio::{sync::Mutex, time::sleep};
+use tokio::sync::Mutex;
 
 use crate::{
     adaptor_signature::AdaptorSignature,
     bitcoincash::{secp256k1::ecdsa, OutPoint},
-    blockchain::{scan_address_conf_tx, TcpElectrum},
-    contract::{ContractPair, TransactionType},
+    blockchain::{
+        broadcaster::{self, PendingTx},
+        xmr, TcpElectrum,
+    },
+    contract::{timelock::Confirmation, ContractPair, TransactionType},
     keys::{KeyPublic, KeyPublicWithoutProof},
     proof,
-    protocol::{Action, Error, Swap, SwapEvents, Transition},
+    protocol::{Action, Error, Outcome, Swap, SwapEvents, Transition},
     utils::{get_signature, monero_key_pair, monero_view_pair},
+    wallet::BchWallet,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -83,26 +83,47 @@ pub enum State {
         monero::Address,
         u64,
     ),
+    /// Xmr from State::SwapSuccess was sent to swap.xmr_recv
+    XmrSwept(Vec<String>),
+    /// The refund contract was spent to bob
+    Refunded,
+    /// Bob did not claim the refund contract before timelock2, alice took it
+    Punished,
 }
 
-impl fmt::Display for State {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl State {
+    pub fn name(&self) -> &'static str {
         match self {
-            State::Init => write!(f, "BobState::Init"),
-            State::WithAliceKey(_) => write!(f, "BobState::WithAliceKey"),
-            State::ContractMatch(_) => write!(f, "BobState::ContractMatch"),
-            State::VerifiedEncSig(_) => write!(f, "BobState::VerifiedEncSig"),
-            State::MoneroLocked(_) => write!(f, "BobState::MoneroLocked"),
-            State::SwapSuccess(_, _, _) => write!(f, "BobState::SwapSuccess"),
-            State::ProceedRefund(_) => write!(f, "BobState::ProceedRefund"),
+            State::Init => "Init",
+            State::WithAliceKey(_) => "WithAliceKey",
+            State::ContractMatch(_) => "ContractMatch",
+            State::VerifiedEncSig(_) => "VerifiedEncSig",
+            State::MoneroLocked(_) => "MoneroLocked",
+            State::SwapSuccess(_, _, _) => "SwapSuccess",
+            State::ProceedRefund(_) => "ProceedRefund",
+            State::XmrSwept(_) => "XmrSwept",
+            State::Refunded => "Refunded",
+            State::Punished => "Punished",
         }
     }
 }
 
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BobState::{}", self.name())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Bob {
     pub state: State,
     pub swap: Swap,
+    /// Contract spends sent but not mined yet
+    #[serde(default)]
+    pub pending_txs: Vec<PendingTx>,
+    /// Why the trade was dropped on an Action::SafeDelete
+    #[serde(default)]
+    pub aborted: Option<String>,
 }
 
 impl Bob {
@@ -110,6 +131,25 @@ impl Bob {
         Bob {
             state: State::Init,
             swap,
+            pending_txs: Vec::new(),
+            aborted: None,
+        }
+    }
+
+    /// Some once nothing is left to send, sweep or watch
+    pub fn outcome(&self) -> Option<Outcome> {
+        if let Some(reason) = &self.aborted {
+            return Some(Outcome::Aborted(reason.clone()));
+        }
+        if !self.pending_txs.is_empty() || self.get_xmr_sweep().is_some() {
+            return None;
+        }
+
+        match self.state {
+            State::SwapSuccess(..) | State::XmrSwept(_) => Some(Outcome::Success),
+            State::Refunded => Some(Outcome::Refunded),
+            State::Punished => Some(Outcome::Punished),
+            _ => None,
         }
     }
 
@@ -152,6 +192,20 @@ impl Bob {
             State::ContractMatch(v) => Some(v.contract_pair),
             State::VerifiedEncSig(v) => Some(v.contract_pair),
             State::MoneroLocked(v) => Some(v.contract_pair),
+            State::ProceedRefund(v) => Some(v.contract_pair),
+            _ => None,
+        }
+    }
+
+    pub fn get_xmr_sweep(&self) -> Option<Action> {
+        match (&self.state, self.swap.xmr_recv) {
+            (State::SwapSuccess(keypair, _, restore_height), Some(destination)) => {
+                Some(Action::SweepXmr {
+                    keypair: *keypair,
+                    restore_height: *restore_height,
+                    destination,
+                })
+            }
             _ => None,
         }
     }
@@ -225,17 +279,53 @@ impl SwapEvents for Bob {
             return (self, vec![], None);
         }
 
+        // The key is in the signature, so no need to wait for confirmations
+        if let Transition::BchMempoolTx(transaction) = transition {
+            let reveals_key = match &self.state {
+                State::MoneroLocked(props) => matches!(
+                    props.contract_pair.analyze_tx(&transaction),
+                    Some((_, TransactionType::SwapLockToAlice))
+                ),
+                _ => false,
+            };
+
+            if !reveals_key {
+                return (self, vec![], None);
+            }
+            return self.transition(Transition::BchConfirmedTx(
+                transaction,
+                Confirmation::default(),
+            ));
+        }
+
         match (self.state.clone(), transition) {
-            (State::Init, Transition::Msg0 { keys, receiving }) => {
+            (
+                State::Init,
+                Transition::Msg0 {
+                    keys,
+                    receiving,
+                    mining_fee,
+                },
+            ) => {
                 let is_valid_keys = proof::verify(&keys.proof, keys.spend_bch, keys.monero_spend);
 
                 if !is_valid_keys {
                     return (self, vec![Action::SafeDelete], Some(Error::InvalidProof));
                 }
 
+                // Our Msg0 reply carries the agreed fee back to alice
+                let Some(mining_fee) = self.swap.negotiate_mining_fee(mining_fee) else {
+                    return (
+                        self,
+                        vec![Action::SafeDelete],
+                        Some(Error::InvalidMiningFee),
+                    );
+                };
+                self.swap.mining_fee = mining_fee;
+
                 let secp = bitcoincash::secp256k1::Secp256k1::signing_only();
                 let contract_pair = ContractPair::create(
-                    1000,
+                    mining_fee,
                     self.swap.bch_recv.clone().into_bytes(),
                     self.swap.keys.ves.public_key(&secp),
                     receiving.clone().into_bytes(),
@@ -361,14 +451,17 @@ impl SwapEvents for Bob {
                 return (self, vec![], None);
             }
 
-            (State::VerifiedEncSig(props), Transition::BchConfirmedTx(transaction, conf)) => {
+            (
+                State::VerifiedEncSig(props),
+                Transition::BchConfirmedTx(transaction, confirmation),
+            ) => {
                 // The runner are still giving prev transaction while alice havent lock xmr
                 // we use it to track if tx sent to swaplock has enough age for refund
 
                 match props.contract_pair.analyze_tx(&transaction) {
-                    // When timelock1 expire
+                    // When the swaplock timelock expires
                     Some((outpoint, TransactionType::ToSwapLock)) => {
-                        if conf < self.swap.timelock1 {
+                        if !confirmation.is_unlocked() {
                             return (self, vec![], None);
                         }
 
@@ -419,12 +512,19 @@ impl SwapEvents for Bob {
                     None => return (self, vec![], Some(Error::InvalidTransaction)),
                 };
 
-                let alice_spend = AdaptorSignature::recover_decryption_key(
+                let Some(alice_spend) = AdaptorSignature::recover_decryption_key(
                     props.alice_keys.spend_bch,
                     decsig,
                     self.get_swaplock_enc_sig()
                         .expect("Enc sig should be open at current state"),
-                );
+                ) else {
+                    return (self, vec![], Some(Error::InvalidTransaction));
+                };
+                if monero::PublicKey::from_private_key(&alice_spend)
+                    != props.alice_keys.monero_spend
+                {
+                    return (self, vec![], Some(Error::InvalidTransaction));
+                }
 
                 let key_pair = monero::KeyPair {
                     view: props.shared_keypair.view,
@@ -437,7 +537,28 @@ impl SwapEvents for Bob {
                     props.xmr_restore_height,
                 );
 
-                return (self, vec![Action::TradeSuccess], None);
+                let mut actions = vec![Action::TradeSuccess];
+                actions.extend(self.get_xmr_sweep());
+                return (self, actions, None);
+            }
+
+            (State::ProceedRefund(props), Transition::BchConfirmedTx(transaction, _)) => {
+                match props.contract_pair.analyze_tx(&transaction) {
+                    Some((_, TransactionType::ToBob)) => {
+                        self.state = State::Refunded;
+                        (self, vec![Action::TradeSuccess], None)
+                    }
+                    Some((_, TransactionType::RefundToAlice)) => {
+                        self.state = State::Punished;
+                        (self, vec![Action::TradeSuccess], None)
+                    }
+                    _ => (self, vec![], None),
+                }
+            }
+
+            (State::SwapSuccess(..), Transition::XmrSwept(tx_hashes)) => {
+                self.state = State::XmrSwept(tx_hashes);
+                (self, vec![], None)
             }
 
             (_, _) => return (self, vec![], Some(Error::InvalidStateTransition)),
@@ -450,7 +571,11 @@ impl SwapEvents for Bob {
             State::WithAliceKey(_) => {
                 let keys = self.get_public_keys();
                 let receiving = self.swap.bch_recv.clone();
-                Some(Transition::Msg0 { keys, receiving })
+                Some(Transition::Msg0 {
+                    keys,
+                    receiving,
+                    mining_fee: self.swap.mining_fee,
+                })
             }
             State::ContractMatch(_) => {
                 let (bch_address, xmr_address) = self.get_contract().unwrap();
@@ -474,11 +599,35 @@ pub struct Runner<'a> {
     pub bch: &'a TcpElectrum,
     pub monerod: &'a monero_rpc::DaemonJsonRpcClient,
     pub monero_wallet: &'a Mutex<monero_rpc::WalletClient>,
+    /// Fund the swaplock automatically when set
+    pub bch_wallet: Option<&'a BchWallet>,
     pub min_bch_conf: u32,
+    /// Key of swap.bch_recv, to pay for stuck spends with a child
+    pub bch_recv_key: bitcoincash::PrivateKey,
 }
 
 impl Runner<'_> {
     pub async fn check_xmr(&mut self) -> anyhow::Result<()> {
+        // Retry the sweep until the bought xmr are unlocked
+        if let Some(Action::SweepXmr {
+            keypair,
+            restore_height,
+            destination,
+        }) = self.inner.get_xmr_sweep()
+        {
+            let tx_hashes = xmr::sweep_all(
+                self.monero_wallet,
+                format!("{}_spend", self.trade_id),
+                self.inner.swap.xmr_network,
+                keypair,
+                restore_height,
+                destination,
+            )
+            .await?;
+
+            return self.priv_transition(Transition::XmrSwept(tx_hashes)).await;
+        }
+
         let monero_wallet = self.monero_wallet.lock().await;
         monero_wallet
             .open_wallet(format!("{}_view", self.trade_id), Some("".to_owned()))
@@ -507,26 +656,32 @@ impl Runner<'_> {
         Ok(())
     }
 
-    pub async fn check_bch(&mut self) -> anyhow::Result<()> {
-        let contract = self.inner.get_contract_pair();
-        if let Some(contract) = contract {
-            let swaplock = contract.swaplock.cash_address();
-            let refund = contract.refund.cash_address();
-            for address in [swaplock, refund].into_iter() {
-                let txs = scan_address_conf_tx(&self.bch, &address, self.min_bch_conf).await;
-                println!("[{}]: {}txs address {}", self.trade_id, txs.len(), address);
-                for (tx, conf) in txs {
-                    let check_bch = self
-                        .priv_transition(Transition::BchConfirmedTx(tx, conf))
-                        .await;
-                    if let Err(check_bch_err) = check_bch {
-                        dbg!(check_bch_err);
-                    }
-                }
+    /// Feed the proven txs of the contracts from a `ScriptWatcher`
+    pub async fn process_bch_txs(&mut self, txs: Vec<(Transaction, Confirmation)>) {
+        for (tx, confirmation) in txs.iter() {
+            let check_bch = self
+                .priv_transition(Transition::BchConfirmedTx(tx.clone(), *confirmation))
+                .await;
+            if let Err(e) = check_bch {
+                println!("[{}]: {e}", self.trade_id);
             }
         }
 
-        Ok(())
+        let bch_recv = BchWallet::new(self.bch_recv_key, self.inner.swap.bch_network);
+        broadcaster::rebroadcast(
+            self.bch,
+            &self.trade_id,
+            &mut self.inner.pending_txs,
+            &txs,
+            &bch_recv,
+        )
+        .await;
+    }
+
+    pub async fn process_bch_mempool_txs(&mut self, txs: Vec<Transaction>) {
+        for tx in txs {
+            let _ = self.priv_transition(Transition::BchMempoolTx(tx)).await;
+        }
     }
 
     pub async fn pub_transition(&mut self, transition: Transition) -> anyhow::Result<()> {
@@ -543,6 +698,12 @@ impl Runner<'_> {
     pub async fn priv_transition(&mut self, transition: Transition) -> anyhow::Result<()> {
         let (mut new_state, actions, error) = self.inner.clone().transition(transition);
         if let Some(err) = error {
+            if actions
+                .iter()
+                .any(|action| matches!(action, Action::SafeDelete))
+            {
+                self.inner.aborted = Some(err.to_string());
+            }
             bail!(err);
         }
 
@@ -570,40 +731,95 @@ impl Runner<'_> {
                         .transition(Transition::SetXmrRestoreHeight(height))
                         .0;
                 }
-                Action::LockBch(amount, addr) => {
-                    let msg = format!("  Send {} to {}  ", amount, addr);
-                    println!("|{:=^width$}|", "", width = msg.len());
-                    println!("|{msg}|");
-                    println!("|{:=^width$}|", "", width = msg.len());
-                }
-                Action::UnlockBchFallback => {
-                    let (tx1, tx2) = new_state.refund().unwrap();
+                Action::LockBch(amount, addr) => match self.bch_wallet {
+                    Some(bch_wallet) => {
+                        let contract = new_state.get_contract_pair().unwrap();
+                        let swaplock = Script::from(contract.swaplock.locking_script());
+                        // queued with the trade, a retry sends the same tx again
+                        let queued = new_state.pending_txs.iter().any(|tx| {
+                            tx.transaction
+                                .output
+                                .iter()
+                                .any(|output| output.script_pubkey == swaplock)
+                        });
+                        if queued {
+                            continue;
+                        }
 
-                    let mut buffer = Vec::new();
-                    tx1.consensus_encode(&mut buffer).unwrap();
-                    let tx_hex: String = buffer.encode_hex();
+                        let (tx, utxos) = bch_wallet.create_tx(self.bch, swaplock, amount).await?;
+                        let input: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+                        let output: u64 = tx.output.iter().map(|output| output.value).sum();
+                        println!(
+                            "[{}]: Funding swaplock {}: {}",
+                            self.trade_id,
+                            addr,
+                            tx.txid()
+                        );
+                        broadcaster::send(
+                            self.bch,
+                            &self.trade_id,
+                            &mut new_state.pending_txs,
+                            tx,
+                            input - output,
+                        )
+                        .await;
+                    }
+                    None => {
+                        let msg = format!("  Send {} to {}  ", amount, addr);
+                        println!("|{:=^width$}|", "", width = msg.len());
+                        println!("|{msg}|");
+                        println!("|{:=^width$}|", "", width = msg.len());
+                    }
+                },
+                Action::UnlockBchFallback => {
+                    let Some((tx1, tx2)) = new_state.refund() else {
+                        continue;
+                    };
+                    let fee = new_state.swap.mining_fee;
 
+                    // tx2 spends tx1, if it is rejected for now the next block retries it
                     println!("Broadcasting tx. SwapLock -> Refund: {}", tx1.txid());
-                    let transaction_resp = self
-                        .bch
-                        .send("blockchain.transaction.broadcast", json!([tx_hex]))
-                        .await
-                        .unwrap();
-                    dbg!(transaction_resp);
-
-                    sleep(Duration::from_secs(5)).await;
-
-                    let mut buffer = Vec::new();
-                    tx2.consensus_encode(&mut buffer).unwrap();
-                    let tx_hex: String = buffer.encode_hex();
+                    broadcaster::send(
+                        self.bch,
+                        &self.trade_id,
+                        &mut new_state.pending_txs,
+                        tx1,
+                        fee,
+                    )
+                    .await;
 
                     println!("Broadcasting tx. Refund -> Bob Output: {}", tx2.txid());
-                    let transaction_resp = self
-                        .bch
-                        .send("blockchain.transaction.broadcast", json!([tx_hex]))
-                        .await
-                        .unwrap();
-                    dbg!(transaction_resp);
+                    broadcaster::send(
+                        self.bch,
+                        &self.trade_id,
+                        &mut new_state.pending_txs,
+                        tx2,
+                        fee,
+                    )
+                    .await;
+                }
+                Action::SweepXmr {
+                    keypair,
+                    restore_height,
+                    destination,
+                } => {
+                    let sweep = xmr::sweep_all(
+                        self.monero_wallet,
+                        format!("{}_spend", self.trade_id),
+                        self.inner.swap.xmr_network,
+                        keypair,
+                        restore_height,
+                        destination,
+                    )
+                    .await;
+
+                    // check_xmr will retry it
+                    match sweep {
+                        Ok(tx_hashes) => {
+                            new_state = new_state.transition(Transition::XmrSwept(tx_hashes)).0
+                        }
+                        Err(e) => println!("[{}]: Sweep failed: {e}", self.trade_id),
+                    }
                 }
                 _ => {}
             }
@@ -0,0 +1,321 @@
+use std::{collections::HashMap, env, fs, path::Path};
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    blockchain::transport::ElectrumServer,
+    keys::bitcoin::Network,
+    protocol::{MAX_MINING_FEE, MIN_MINING_FEE},
+    utils::monero_network,
+};
+
+/// Settings shared by the server and the clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeConfig {
+    pub monerod_addr: String,
+    pub monero_wallet_addr: String,
+    pub electrum_addr: String,
+    /// Tried in order when `electrum_addr` is unreachable
+    pub electrum_fallbacks: Vec<String>,
+    pub electrum_timeout_secs: u64,
+
+    #[serde(with = "monero_network")]
+    pub monero_network: monero::Network,
+    pub bch_network: Network,
+
+    pub bch_min_conf: u32,
+    /// React to spends revealing a key before they are mined
+    pub bch_mempool: bool,
+    pub timelock1: u32,
+    pub timelock2: u32,
+    /// Sats per contract spend we agree to. The counterparty
+    /// can push the fee up, but never past `max_mining_fee`
+    pub min_mining_fee: u64,
+    pub max_mining_fee: u64,
+
+    /// Where to sweep the xmr once we hold the shared spend key
+    pub xmr_recv: Option<monero::Address>,
+    /// WIF of the P2PKH wallet funding the swaplock
+    pub bch_wallet_wif: Option<String>,
+    /// monero-wallet-rpc wallet used to lock the xmr
+    pub xmr_wallet: Option<String>,
+    pub xmr_wallet_password: String,
+    /// Encrypts the trade files, which hold the swap private keys
+    pub trade_passphrase: Option<String>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            monerod_addr: "http://localhost:18081".to_owned(),
+            monero_wallet_addr: "http://localhost:8081".to_owned(),
+            electrum_addr: "localhost:50001".to_owned(),
+            electrum_fallbacks: Vec::new(),
+            electrum_timeout_secs: 30,
+            monero_network: monero::Network::Mainnet,
+            bch_network: Network::Regtest,
+            bch_min_conf: 1,
+            bch_mempool: true,
+            timelock1: 20,
+            timelock2: 20,
+            min_mining_fee: MIN_MINING_FEE,
+            max_mining_fee: MAX_MINING_FEE,
+            xmr_recv: None,
+            bch_wallet_wif: None,
+            xmr_wallet: None,
+            xmr_wallet_password: "".to_owned(),
+            trade_passphrase: None,
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn electrum_servers(&self) -> anyhow::Result<Vec<ElectrumServer>> {
+        std::iter::once(&self.electrum_addr)
+            .chain(&self.electrum_fallbacks)
+            .map(|server| server.parse().map_err(anyhow::Error::msg))
+            .collect()
+    }
+
+    /// Refuse to trade real coins against test coins
+    pub fn check_networks(&self) -> anyhow::Result<()> {
+        let compatible = match self.bch_network {
+            Network::Mainnet => matches!(self.monero_network, monero::Network::Mainnet),
+            Network::Testnet => matches!(
+                self.monero_network,
+                monero::Network::Testnet | monero::Network::Stagenet
+            ),
+            // monerod --regtest uses mainnet addresses
+            Network::Regtest => matches!(self.monero_network, monero::Network::Mainnet),
+        };
+
+        if !compatible {
+            bail!(
+                "Bch {:?} cannot be used with monero {:?}",
+                self.bch_network,
+                self.monero_network
+            );
+        }
+
+        if let Some(address) = self.xmr_recv {
+            if address.network != self.monero_network {
+                bail!("xmr_recv is not a monero {:?} address", self.monero_network);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_mining_fees(&self) -> anyhow::Result<()> {
+        if self.min_mining_fee > self.max_mining_fee {
+            bail!(
+                "min_mining_fee {} is above max_mining_fee {}",
+                self.min_mining_fee,
+                self.max_mining_fee
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Build `T` from its defaults, then a TOML or JSON file,
+/// then env vars, then `--key value` arguments. Later sources win.
+///
+/// The file is given by `--config <path>` or the `CONFIG` env var.
+/// Env vars are the upper-cased keys, e.g. `BCH_MIN_CONF`.
+/// Arguments not starting with `--` are left to the caller
+pub fn load<T>() -> anyhow::Result<T>
+where
+    T: Default + Serialize + DeserializeOwned,
+{
+    load_from(env::vars().collect(), env::args().skip(1).collect())
+}
+
+fn load_from<T>(vars: HashMap<String, String>, args: Vec<String>) -> anyhow::Result<T>
+where
+    T: Default + Serialize + DeserializeOwned,
+{
+    let mut config = match serde_json::to_value(T::default())? {
+        Value::Object(map) => map,
+        _ => bail!("Config must be a struct"),
+    };
+
+    let args = parse_args(&args)?;
+    let path = args
+        .iter()
+        .rev()
+        .find(|(key, _)| key == "config")
+        .map(|(_, path)| path.clone())
+        .or_else(|| vars.get("CONFIG").cloned());
+
+    if let Some(path) = path {
+        for (key, value) in read_file(Path::new(&path))? {
+            set(&mut config, &key, value)?;
+        }
+    }
+
+    let keys: Vec<String> = config.keys().cloned().collect();
+    for key in keys {
+        if let Some(raw) = vars.get(&key.to_uppercase()) {
+            set_raw(&mut config, &key, raw)?;
+        }
+    }
+
+    for (key, raw) in args.iter().filter(|(key, _)| key != "config") {
+        set_raw(&mut config, key, raw)?;
+    }
+
+    serde_json::from_value(Value::Object(config)).context("Invalid config")
+}
+
+fn parse_args(args: &[String]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut parsed = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(arg) = arg.strip_prefix("--") else {
+            continue;
+        };
+
+        let (key, value) = match arg.split_once('=') {
+            Some((key, value)) => (key, value.to_owned()),
+            None => match args.next() {
+                Some(value) => (arg, value.clone()),
+                None => bail!("Missing value for --{arg}"),
+            },
+        };
+        parsed.push((key.replace('-', "_"), value));
+    }
+
+    Ok(parsed)
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Map<String, Value>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read config {}", path.display()))?;
+
+    let value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str::<Value>(&content)?,
+        _ => serde_json::to_value(toml::from_str::<toml::Value>(&content)?)?,
+    };
+
+    match value {
+        Value::Object(map) => Ok(map),
+        _ => bail!("{} must be a table", path.display()),
+    }
+}
+
+fn set(config: &mut Map<String, Value>, key: &str, value: Value) -> anyhow::Result<()> {
+    match config.get_mut(key) {
+        Some(current) => {
+            *current = value;
+            Ok(())
+        }
+        None => bail!("Unknown config key {key}"),
+    }
+}
+
+/// Env vars and args are strings, so parse them by the current value type.
+/// Lists are comma separated
+fn set_raw(config: &mut Map<String, Value>, key: &str, raw: &str) -> anyhow::Result<()> {
+    let value = match config.get(key) {
+        Some(Value::Number(_) | Value::Bool(_)) => {
+            serde_json::from_str(raw).with_context(|| format!("Invalid value for {key}: {raw}"))?
+        }
+        Some(Value::Array(_)) => raw
+            .split(',')
+            .map(|item| Value::String(item.trim().to_owned()))
+            .collect(),
+        _ => Value::String(raw.to_owned()),
+    };
+
+    set(config, key, value)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, fs};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{load_from, NodeConfig};
+    use crate::keys::bitcoin::Network;
+
+    #[derive(Default, Serialize, Deserialize)]
+    #[serde(default)]
+    struct TestConfig {
+        #[serde(flatten)]
+        node: NodeConfig,
+        port: u16,
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn should_override_in_order() {
+        let path = std::env::temp_dir().join("bch-xmr-swap-config-test.toml");
+        fs::write(&path, "port = 1\nbch_min_conf = 1\ntimelock1 = 5\n").unwrap();
+
+        let vars = HashMap::from([
+            ("CONFIG".to_owned(), path.to_str().unwrap().to_owned()),
+            ("PORT".to_owned(), "2".to_owned()),
+            ("BCH_MIN_CONF".to_owned(), "2".to_owned()),
+            ("BCH_NETWORK".to_owned(), "Testnet".to_owned()),
+            ("ELECTRUM_FALLBACKS".to_owned(), "a:1, ssl://b:2".to_owned()),
+        ]);
+        let config: TestConfig = load_from(
+            vars,
+            args(&["trade_id", "--port", "3", "--monero-network=Stagenet"]),
+        )
+        .unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(config.node.timelock1, 5);
+        assert_eq!(config.node.bch_min_conf, 2);
+        assert_eq!(config.port, 3);
+        assert!(matches!(config.node.bch_network, Network::Testnet));
+        assert_eq!(config.node.monero_network, monero::Network::Stagenet);
+        assert!(config.node.check_networks().is_ok());
+        let servers = config.node.electrum_servers().unwrap();
+        let servers: Vec<String> = servers.iter().map(|s| s.to_string()).collect();
+        assert_eq!(servers, ["localhost:50001", "a:1", "ssl://b:2"]);
+    }
+
+    #[test]
+    fn should_reject_unknown_key() {
+        let config = load_from::<TestConfig>(HashMap::new(), args(&["--prot", "3"]));
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn should_reject_incompatible_networks() {
+        let config = NodeConfig {
+            bch_network: Network::Mainnet,
+            monero_network: monero::Network::Stagenet,
+            ..Default::default()
+        };
+        assert!(config.check_networks().is_err());
+    }
+
+    #[test]
+    fn should_check_mining_fees() {
+        let config = NodeConfig {
+            min_mining_fee: 1000,
+            max_mining_fee: 5000,
+            ..Default::default()
+        };
+        assert!(config.check_mining_fees().is_ok());
+
+        let config = NodeConfig {
+            min_mining_fee: 5000,
+            max_mining_fee: 1000,
+            ..Default::default()
+        };
+        assert!(config.check_mining_fees().is_err());
+    }
+}
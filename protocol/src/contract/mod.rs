@@ -10,9 +10,11 @@ use bitcoincash::{
 use serde::{Deserialize, Serialize};
 
 use crate::keys::bitcoin::{address, Network};
+use timelock::Timelock;
+
+pub mod timelock;
 
 const CONTRACT_BYTECODE: [u8; 47] = hex_literal::hex!("c3519dc4519d00c600cc949d00cb009c6300cd7888547978a85379bb675279b27500cd54798854790088686d6d7551");
-const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff; // bip68
 
 #[derive(Debug)]
 pub enum TransactionType {
@@ -36,7 +38,7 @@ pub struct Contract {
     pub bch_network: Network,
 
     // allow others to read struct field, but not creation
-    // We need to check given timelock. SEQUENCE_LOCKTIME_MASK
+    // We need to check given timelock, see Timelock::from_sequence
     _private: (),
 }
 
@@ -55,6 +57,11 @@ impl Contract {
         contract
     }
 
+    /// None if `timelock` is not a BIP68 relative lock
+    pub fn relative_timelock(&self) -> Option<Timelock> {
+        Timelock::from_sequence(self.timelock)
+    }
+
     #[inline]
     pub fn script_hash(&self) -> [u8; 20] {
         hash160::Hash::hash(&self.script()).to_byte_array()
@@ -106,7 +113,7 @@ pub struct ContractPair {
 }
 
 impl ContractPair {
-    // None variant is timelock > SEQUENCE_LOCKTIME_MASK
+    // None variant is a timelock that is not a BIP68 relative lock
     pub fn create(
         mining_fee: u64,
         bob_receiving: Vec<u8>,
@@ -118,7 +125,9 @@ impl ContractPair {
         bch_network: Network,
         swaplock_in: bitcoincash::Amount,
     ) -> Option<ContractPair> {
-        if timelock0 > SEQUENCE_LOCKTIME_MASK || timelock1 > SEQUENCE_LOCKTIME_MASK {
+        if Timelock::from_sequence(timelock0).is_none()
+            || Timelock::from_sequence(timelock1).is_none()
+        {
             return None;
         }
 
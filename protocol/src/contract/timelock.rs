@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+/// Time based locks count units of 512 seconds
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+/// Relative lock of a contract timelock branch, read like BIP68 reads
+/// the sequence checked by `OP_CHECKSEQUENCEVERIFY`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Timelock {
+    Blocks(u16),
+    /// Units of 512 seconds of median time past
+    Time(u16),
+}
+
+impl Timelock {
+    /// None if other bits than the type flag and the value are set,
+    /// e.g. the disable flag
+    pub fn from_sequence(sequence: u32) -> Option<Timelock> {
+        if sequence & !(SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK) != 0 {
+            return None;
+        }
+
+        let value = (sequence & SEQUENCE_LOCKTIME_MASK) as u16;
+        match sequence & SEQUENCE_LOCKTIME_TYPE_FLAG {
+            0 => Some(Timelock::Blocks(value)),
+            _ => Some(Timelock::Time(value)),
+        }
+    }
+
+    pub fn to_sequence(self) -> u32 {
+        match self {
+            Timelock::Blocks(blocks) => blocks as u32,
+            Timelock::Time(units) => SEQUENCE_LOCKTIME_TYPE_FLAG | units as u32,
+        }
+    }
+
+    /// Height of the first block that may spend an output mined at `height`.
+    ///
+    /// Time based locks are known once the median time past of the chain,
+    /// given by `median_time` up to `tip`, reaches them. None until then
+    pub fn unlock_height(
+        self,
+        height: u32,
+        tip: u32,
+        median_time: impl Fn(u32) -> Option<u32>,
+    ) -> Option<u32> {
+        let units = match self {
+            Timelock::Blocks(blocks) => return Some(height + blocks as u32),
+            Timelock::Time(units) => units as u32,
+        };
+
+        // a block is checked against the median time past of its parent
+        let unlock_time = median_time(height.checked_sub(1)?)?
+            .saturating_add(units << SEQUENCE_LOCKTIME_GRANULARITY);
+        if median_time(tip)? < unlock_time {
+            return None;
+        }
+
+        // the median time past never decreases
+        let (mut low, mut high) = (height, tip);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if median_time(middle)? < unlock_time {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        Some(low + 1)
+    }
+}
+
+/// A contract tx as seen from the chain tip
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Confirmation {
+    /// Height of the block holding the tx, 0 if unconfirmed
+    pub height: u32,
+    pub tip: u32,
+    /// First height at which the contract output of the tx
+    /// can take its timelock branch, if known yet
+    pub unlock_height: Option<u32>,
+}
+
+impl Confirmation {
+    pub fn confirmations(&self) -> u32 {
+        match self.height {
+            0 => 0,
+            // the tip may not be synced yet
+            height => (self.tip + 1).saturating_sub(height).max(1),
+        }
+    }
+
+    /// Whether the timelock branch can be spent in the next block
+    pub fn is_unlocked(&self) -> bool {
+        self.unlock_height
+            .is_some_and(|unlock_height| unlock_height <= self.tip + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Confirmation, Timelock};
+
+    #[test]
+    fn should_read_bip68_sequence() {
+        assert_eq!(Timelock::from_sequence(20), Some(Timelock::Blocks(20)));
+        assert_eq!(
+            Timelock::from_sequence(1 << 22 | 20),
+            Some(Timelock::Time(20))
+        );
+        assert_eq!(Timelock::from_sequence(1 << 31 | 20), None);
+        assert_eq!(Timelock::from_sequence(0x10000), None);
+        assert_eq!(Timelock::Time(20).to_sequence(), 1 << 22 | 20);
+    }
+
+    #[test]
+    fn should_compute_unlock_height() {
+        // one block every 10 minutes, median time past lags 5 blocks behind
+        let median_time = |height: u32| Some(height.saturating_sub(5) * 600);
+
+        assert_eq!(
+            Timelock::Blocks(20).unlock_height(100, 100, median_time),
+            Some(120)
+        );
+
+        // 2 units of 512s from the median time past of block 99
+        let lock = Timelock::Time(2);
+        assert_eq!(lock.unlock_height(100, 100, median_time), None);
+        assert_eq!(lock.unlock_height(100, 101, median_time), Some(102));
+        assert_eq!(lock.unlock_height(100, 150, median_time), Some(102));
+
+        let confirmation = Confirmation {
+            height: 100,
+            tip: 118,
+            unlock_height: Some(120),
+        };
+        assert_eq!(confirmation.confirmations(), 19);
+        assert!(!confirmation.is_unlocked());
+        assert!(Confirmation {
+            tip: 119,
+            ..confirmation
+        }
+        .is_unlocked());
+        assert_eq!(Confirmation::default().confirmations(), 0);
+    }
+}
@@ -4,12 +4,15 @@ pub mod adaptor_signature;
 pub mod alice;
 pub mod blockchain;
 pub mod bob;
+pub mod config;
 pub mod contract;
 pub mod keys;
+pub mod lifecycle;
 pub mod persist;
 pub mod proof;
 pub mod protocol;
 pub(crate) mod utils;
+pub mod wallet;
 
 pub use bitcoincash;
 pub use monero;
@@ -0,0 +1,41 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    blockchain::{watch::ScriptWatcher, TcpElectrum},
+    persist::{Completion, Error, SwapStore, TradePersist},
+    protocol::Outcome,
+};
+
+/// Moves trades out of the ongoing ones once they are over
+pub struct Lifecycle<'a> {
+    pub store: &'a dyn SwapStore,
+    pub bch: &'a TcpElectrum,
+    pub watcher: &'a ScriptWatcher,
+}
+
+impl Lifecycle<'_> {
+    /// Save the trade, or archive it with a completion record
+    /// and stop watching its contracts once it is over
+    pub async fn save(&self, mut trade: TradePersist) -> Result<Option<Outcome>, Error> {
+        let Some(outcome) = trade.config.swap.outcome() else {
+            self.store.save(&trade).await?;
+            return Ok(None);
+        };
+
+        let trade_id = trade.trade_id.clone();
+        trade.config.completion = Some(Completion {
+            outcome: outcome.clone(),
+            completed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+        });
+        self.store.archive(trade).await?;
+        println!("[{trade_id}]: Archived, {outcome:?}");
+
+        if let Err(e) = self.watcher.unwatch(self.bch, &trade_id).await {
+            println!("[{trade_id}]: Cannot unwatch contracts: {e}");
+        }
+
+        Ok(Some(outcome))
+    }
+}
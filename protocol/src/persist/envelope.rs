@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Key, XChaCha20Poly1305,
+};
+use rand::RngCore;
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+
+use super::Error;
+
+/// Bumped whenever the kdf or the cipher changes
+pub const VERSION: u8 = 1;
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+
+/// Argon2id parameters and salt the file key is derived with
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Kdf {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    #[serde(with = "hex")]
+    pub salt: [u8; SALT_SIZE],
+}
+
+impl Kdf {
+    /// OWASP recommended argon2id settings, with a fresh salt
+    fn random() -> Self {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        Kdf {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+            salt,
+        }
+    }
+
+    fn derive(&self, passphrase: &str) -> Result<Key, Error> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))?;
+
+        let mut key = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(
+            passphrase.as_bytes(),
+            &self.salt,
+            &mut key,
+        )?;
+        Ok(key)
+    }
+}
+
+/// Left in clear and authenticated along with the ciphertext
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u8,
+    pub kdf: Kdf,
+    #[serde(with = "hex")]
+    pub nonce: [u8; NONCE_SIZE],
+}
+
+/// An encrypted trade file, the config sealed with XChaCha20-Poly1305
+#[derive(Serialize, Deserialize)]
+pub struct Envelope {
+    pub encryption: Header,
+    #[serde(with = "hex")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    /// None if `data` is a plain trade file
+    pub fn parse(data: &[u8]) -> Result<Option<Envelope>, Error> {
+        #[derive(Deserialize)]
+        struct Probe {
+            encryption: Option<IgnoredAny>,
+        }
+
+        match serde_json::from_slice::<Probe>(data)?.encryption {
+            None => Ok(None),
+            Some(_) => Ok(Some(serde_json::from_slice(data)?)),
+        }
+    }
+}
+
+/// A passphrase and the keys already derived from it.
+///
+/// Deriving is slow on purpose, so keys are kept per salt
+/// and new files all use the salt picked at creation
+pub struct Keyring {
+    passphrase: String,
+    kdf: Kdf,
+    keys: Mutex<HashMap<Kdf, Key>>,
+}
+
+impl Keyring {
+    pub fn new(passphrase: &str) -> Self {
+        Keyring {
+            passphrase: passphrase.to_owned(),
+            kdf: Kdf::random(),
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn key(&self, kdf: &Kdf) -> Result<Key, Error> {
+        let mut keys = self.keys.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(key) = keys.get(kdf) {
+            return Ok(*key);
+        }
+
+        let key = kdf.derive(&self.passphrase)?;
+        keys.insert(kdf.clone(), key);
+        Ok(key)
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Envelope, Error> {
+        let header = Header {
+            version: VERSION,
+            kdf: self.kdf.clone(),
+            nonce: XChaCha20Poly1305::generate_nonce(&mut OsRng).into(),
+        };
+
+        let cipher = XChaCha20Poly1305::new(&self.key(&header.kdf)?);
+        let ciphertext = cipher.encrypt(
+            &header.nonce.into(),
+            Payload {
+                msg: plaintext,
+                aad: &serde_json::to_vec(&header)?,
+            },
+        )?;
+
+        Ok(Envelope {
+            encryption: header,
+            ciphertext,
+        })
+    }
+
+    pub fn open(&self, envelope: &Envelope) -> Result<Vec<u8>, Error> {
+        let header = &envelope.encryption;
+        if header.version != VERSION {
+            return Err(Error::Unknown(format!(
+                "Unsupported encryption version {}",
+                header.version
+            )));
+        }
+
+        let cipher = XChaCha20Poly1305::new(&self.key(&header.kdf)?);
+        cipher
+            .decrypt(
+                &header.nonce.into(),
+                Payload {
+                    msg: &envelope.ciphertext,
+                    aad: &serde_json::to_vec(header)?,
+                },
+            )
+            .map_err(|_| Error::Unknown("Wrong passphrase or tampered trade file".to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Envelope, Kdf, Keyring};
+
+    fn fast_keyring(passphrase: &str) -> Keyring {
+        let mut keyring = Keyring::new(passphrase);
+        // fast enough for tests
+        keyring.kdf = Kdf {
+            memory_kib: 64,
+            iterations: 1,
+            ..keyring.kdf
+        };
+        keyring
+    }
+
+    #[test]
+    fn should_seal_and_open() {
+        let keyring = fast_keyring("correct horse");
+        let envelope = keyring.seal(b"{\"swap\":1}").unwrap();
+
+        let data = serde_json::to_vec(&envelope).unwrap();
+        let envelope = Envelope::parse(&data).unwrap().unwrap();
+        assert_eq!(keyring.open(&envelope).unwrap(), b"{\"swap\":1}");
+
+        assert!(fast_keyring("battery staple").open(&envelope).is_err());
+
+        let mut tampered = Envelope::parse(&data).unwrap().unwrap();
+        tampered.ciphertext[0] ^= 1;
+        assert!(keyring.open(&tampered).is_err());
+
+        assert!(Envelope::parse(b"{\"swap\":1}").unwrap().is_none());
+    }
+}
@@ -0,0 +1,447 @@
+use std::{io::ErrorKind, path::Path};
+
+use bitcoin_hashes::{sha256, Hash};
+use fs4::tokio::AsyncFileExt;
+use tokio::{
+    fs::{self, File},
+    io::AsyncWriteExt,
+};
+
+use super::{
+    decode, encode, envelope::Envelope, Config, Error, Keyring, SwapStore, TradeInfo, TradePersist,
+    KEYRING,
+};
+
+/// Previous states kept next to the trade file, `.bak.1` being the newest
+const BACKUPS: usize = 3;
+const CHECKSUM_PREFIX: &str = "sha256:";
+
+/// One json file per trade, `{root}/ongoing/{trade_id}{suffix}`,
+/// moved to `{root}/success/` once archived.
+///
+/// Each save goes to a temp file renamed over the trade file,
+/// so a crash leaves either the old or the new state.
+/// The json is preceded by a `sha256:<hex>` line, checked on load
+pub struct FileStore {
+    root: String,
+    suffix: String,
+}
+
+impl FileStore {
+    pub fn new(root: &str, suffix: &str) -> Self {
+        FileStore {
+            root: root.to_owned(),
+            suffix: suffix.to_owned(),
+        }
+    }
+
+    pub fn file_path(&self, trade_id: &str) -> String {
+        format!("{}/ongoing/{trade_id}{}", self.root, self.suffix)
+    }
+
+    fn archive_path(&self, trade_id: &str) -> String {
+        format!("{}/success/{trade_id}{}", self.root, self.suffix)
+    }
+}
+
+#[async_trait::async_trait]
+impl SwapStore for FileStore {
+    async fn create(&self, trade_id: &str, config: Config) -> Result<(), Error> {
+        let file_path = self.file_path(trade_id);
+        // checked under the lock, so concurrent creates cannot both pass
+        let _lock = lock(&file_path).await?;
+        if fs::try_exists(&file_path).await? {
+            return Err(Error::Unknown(format!("{file_path} already exists")));
+        }
+
+        let temp_path = write_temp(&file_path, &config, KEYRING.get()).await?;
+        replace(&temp_path, &file_path).await
+    }
+
+    async fn list(&self) -> Result<Vec<TradeInfo>, Error> {
+        let mut trades = Vec::new();
+        let mut entries = fs::read_dir(format!("{}/ongoing", self.root)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let Some(trade_id) = file_name.strip_suffix(&self.suffix) else {
+                continue;
+            };
+
+            match read_or_backup(&self.file_path(trade_id)).await {
+                Ok(config) => trades.push(TradeInfo {
+                    trade_id: trade_id.to_owned(),
+                    state: config.swap.state().to_owned(),
+                }),
+                Err(e) => eprintln!("[{trade_id}]: Cannot read trade: {e:?}"),
+            }
+        }
+
+        Ok(trades)
+    }
+
+    async fn load(&self, trade_id: &str) -> Result<TradePersist, Error> {
+        let file_path = self.file_path(trade_id);
+        if !fs::try_exists(&file_path).await? {
+            return Err(Error::NotFound);
+        }
+        let lock = lock(&file_path).await?;
+
+        Ok(TradePersist {
+            trade_id: trade_id.to_owned(),
+            config: read_or_backup(&file_path).await?,
+            _lock: Box::new(lock),
+        })
+    }
+
+    async fn save(&self, trade: &TradePersist) -> Result<(), Error> {
+        let file_path = self.file_path(&trade.trade_id);
+        let temp_path = write_temp(&file_path, &trade.config, KEYRING.get()).await?;
+        rotate_backups(&file_path, KEYRING.get()).await?;
+        replace(&temp_path, &file_path).await
+    }
+
+    async fn archive(&self, trade: TradePersist) -> Result<(), Error> {
+        let file_path = self.file_path(&trade.trade_id);
+        let archive_path = self.archive_path(&trade.trade_id);
+        let temp_path = write_temp(&archive_path, &trade.config, KEYRING.get()).await?;
+        replace(&temp_path, &archive_path).await?;
+
+        fs::remove_file(&file_path).await?;
+        for index in 1..=BACKUPS {
+            let _ = fs::remove_file(backup_path(&file_path, index)).await;
+        }
+        let _ = fs::remove_file(lock_path(&file_path)).await;
+
+        Ok(())
+    }
+
+    async fn reencrypt(&self, old: Option<&Keyring>, new: Option<&Keyring>) -> Result<(), Error> {
+        for dir in ["ongoing", "success"] {
+            let dir = format!("{}/{dir}", self.root);
+            if !fs::try_exists(&dir).await? {
+                continue;
+            }
+
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                if file_name.ends_with(&self.suffix) {
+                    reencrypt(&format!("{dir}/{file_name}"), old, new).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decrypt the trade file and its backups with `old`, then encrypt them with `new`.
+/// `None` stands for plain json
+pub async fn reencrypt(
+    file_path: &str,
+    old: Option<&Keyring>,
+    new: Option<&Keyring>,
+) -> Result<(), Error> {
+    if !fs::try_exists(file_path).await? {
+        return Err(Error::NotFound);
+    }
+    let _lock = lock(file_path).await?;
+
+    let backups = (1..=BACKUPS).map(|index| backup_path(file_path, index));
+    for path in std::iter::once(file_path.to_owned()).chain(backups) {
+        let config = match read(&path, old).await {
+            Ok(config) => config,
+            Err(Error::NotFound) => continue,
+            Err(Error::Unknown(e)) => return Err(Error::Unknown(format!("{path}: {e}"))),
+        };
+        let temp_path = write_temp(&path, &config, new).await?;
+        replace(&temp_path, &path).await?;
+    }
+
+    Ok(())
+}
+
+fn lock_path(file_path: &str) -> String {
+    format!("{file_path}.lock")
+}
+
+fn backup_path(file_path: &str, index: usize) -> String {
+    format!("{file_path}.bak.{index}")
+}
+
+async fn lock(file_path: &str) -> Result<File, Error> {
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(file_path))
+        .await?;
+    lock.lock_exclusive()?;
+
+    Ok(lock)
+}
+
+async fn read(file_path: &str, keyring: Option<&Keyring>) -> Result<Config, Error> {
+    let data = match fs::read(file_path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(e) => return Err(Error::from(e)),
+    };
+
+    decode(verify(&data)?, keyring)
+}
+
+/// The trade, or its newest valid backup if the file is corrupted
+async fn read_or_backup(file_path: &str) -> Result<Config, Error> {
+    match read(file_path, KEYRING.get()).await {
+        Ok(config) => Ok(config),
+        Err(e) => {
+            eprintln!("Cannot read {file_path}: {e:?}");
+            restore_backup(file_path).await.ok_or(e)
+        }
+    }
+}
+
+/// The json body, after checking it against the checksum line.
+/// Files written before checksums are plain json
+fn verify(data: &[u8]) -> Result<&[u8], Error> {
+    let Some(rest) = data.strip_prefix(CHECKSUM_PREFIX.as_bytes()) else {
+        return Ok(data);
+    };
+
+    let Some(newline) = rest.iter().position(|byte| *byte == b'\n') else {
+        return Err(Error::Unknown("Missing checksum line".to_owned()));
+    };
+    let (checksum, body) = (&rest[..newline], &rest[newline + 1..]);
+    if checksum != sha256::Hash::hash(body).to_string().as_bytes() {
+        return Err(Error::Unknown("Checksum mismatch".to_owned()));
+    }
+
+    Ok(body)
+}
+
+async fn restore_backup(file_path: &str) -> Option<Config> {
+    for index in 1..=BACKUPS {
+        let path = backup_path(file_path, index);
+        match read(&path, KEYRING.get()).await {
+            Ok(config) => {
+                eprintln!("Restored {file_path} from {path}");
+                return Some(config);
+            }
+            Err(Error::NotFound) => {}
+            Err(e) => eprintln!("Cannot read {path}: {e:?}"),
+        }
+    }
+
+    None
+}
+
+/// Write `config` to `{file_path}.tmp`, ready to be renamed over `file_path`
+async fn write_temp(
+    file_path: &str,
+    config: &Config,
+    keyring: Option<&Keyring>,
+) -> Result<String, Error> {
+    let body = encode(config, keyring)?;
+    let checksum = sha256::Hash::hash(&body);
+
+    let temp_path = format!("{file_path}.tmp");
+    let mut temp = File::create(&temp_path).await?;
+    temp.write_all(format!("{CHECKSUM_PREFIX}{checksum}\n").as_bytes())
+        .await?;
+    temp.write_all(&body).await?;
+    temp.sync_all().await?;
+
+    Ok(temp_path)
+}
+
+async fn replace(temp_path: &str, file_path: &str) -> Result<(), Error> {
+    fs::rename(temp_path, file_path).await?;
+    sync_dir(file_path).await
+}
+
+/// Shift the backups and write the current state to `.bak.1`,
+/// encrypted with `keyring` like the trade file itself
+async fn rotate_backups(file_path: &str, keyring: Option<&Keyring>) -> Result<(), Error> {
+    let config = match read(file_path, keyring).await {
+        Ok(config) => config,
+        Err(Error::NotFound) => return Ok(()),
+        // keep the older backups rather than a copy of a corrupted file
+        Err(e) => {
+            eprintln!("Cannot back up {file_path}: {e:?}");
+            return Ok(());
+        }
+    };
+
+    for index in (1..BACKUPS).rev() {
+        match fs::rename(
+            backup_path(file_path, index),
+            backup_path(file_path, index + 1),
+        )
+        .await
+        {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(Error::from(e)),
+            _ => {}
+        }
+    }
+    let backup = backup_path(file_path, 1);
+    let temp_path = write_temp(&backup, &config, keyring).await?;
+    replace(&temp_path, &backup).await?;
+
+    if let Some(keyring) = keyring {
+        for index in 2..=BACKUPS {
+            let path = backup_path(file_path, index);
+            if let Err(e) = seal_backup(&path, keyring).await {
+                eprintln!("Cannot encrypt {path}: {e:?}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Encrypt a backup written before the passphrase was set
+async fn seal_backup(path: &str, keyring: &Keyring) -> Result<(), Error> {
+    let data = match fs::read(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::from(e)),
+    };
+    let body = verify(&data)?;
+    if Envelope::parse(body)?.is_some() {
+        return Ok(());
+    }
+
+    let temp_path = write_temp(path, &decode(body, None)?, Some(keyring)).await?;
+    replace(&temp_path, path).await
+}
+
+/// Persist the rename itself
+async fn sync_dir(file_path: &str) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        let dir = match Path::new(file_path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir).await?.sync_all().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bitcoin_hashes::{sha256, Hash};
+    use tokio::fs;
+
+    use super::FileStore;
+    use super::{backup_path, read, replace, rotate_backups, verify, write_temp, CHECKSUM_PREFIX};
+    use crate::persist::{decode, envelope::Envelope, Config, Keyring, SwapStore};
+
+    fn config() -> Config {
+        let fixture = include_str!("fixtures/v1-alice-valid-enc-sig.json");
+        decode(fixture.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn should_verify_checksum() {
+        let body = b"{\"swap\":1}";
+        let checksum = sha256::Hash::hash(body);
+        let data = [
+            format!("{CHECKSUM_PREFIX}{checksum}\n").as_bytes(),
+            &body[..],
+        ]
+        .concat();
+
+        assert_eq!(verify(&data).unwrap(), body);
+        assert!(verify(&data[..data.len() - 1]).is_err());
+        // written before checksums
+        assert_eq!(verify(body).unwrap(), body);
+    }
+
+    /// A fresh directory under the system temp dir
+    async fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(dir.join("ongoing")).await.unwrap();
+        fs::create_dir_all(dir.join("success")).await.unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn should_encrypt_backups() {
+        let dir = temp_dir("encrypt-backups").await;
+        let file_path = format!("{dir}/trade.json");
+        let config = config();
+
+        // saved twice before the passphrase was set
+        for path in [backup_path(&file_path, 1), file_path.clone()] {
+            let temp_path = write_temp(&path, &config, None).await.unwrap();
+            replace(&temp_path, &path).await.unwrap();
+        }
+
+        let keyring = Keyring::new("correct horse");
+        rotate_backups(&file_path, Some(&keyring)).await.unwrap();
+
+        for index in 1..=2 {
+            let path = backup_path(&file_path, index);
+            let data = fs::read(&path).await.unwrap();
+            let body = verify(&data).unwrap();
+            assert!(Envelope::parse(body).unwrap().is_some(), "{path}");
+            assert!(read(&path, Some(&keyring)).await.is_ok());
+        }
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    // the file lock blocks its thread
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn should_create_once() {
+        let dir = temp_dir("create-once").await;
+        let store = Arc::new(FileStore::new(&dir, ".json"));
+
+        let creates = (0..2).map(|_| {
+            let store = store.clone();
+            tokio::spawn(async move { store.create("trade", config()).await })
+        });
+        let mut created = 0;
+        for create in creates.collect::<Vec<_>>() {
+            created += create.await.unwrap().is_ok() as usize;
+        }
+        assert_eq!(created, 1);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_restore_from_backup() {
+        let dir = temp_dir("restore-backup").await;
+        let store = FileStore::new(&dir, ".json");
+        store.create("trade", config()).await.unwrap();
+        assert!(store.create("trade", config()).await.is_err());
+
+        let trade = store.load("trade").await.unwrap();
+        store.save(&trade).await.unwrap();
+        drop(trade);
+
+        let file_path = store.file_path("trade");
+        assert!(fs::try_exists(backup_path(&file_path, 1)).await.unwrap());
+        assert!(!fs::try_exists(format!("{file_path}.tmp")).await.unwrap());
+
+        // torn write
+        let data = fs::read(&file_path).await.unwrap();
+        fs::write(&file_path, &data[..data.len() / 2])
+            .await
+            .unwrap();
+
+        let trade = store.load("trade").await.unwrap();
+        assert_eq!(trade.config.swap.state(), "ValidEncSig");
+        drop(trade);
+        assert_eq!(store.list().await.unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
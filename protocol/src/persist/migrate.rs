@@ -0,0 +1,152 @@
+use serde_json::{json, Map, Value};
+
+use super::Error;
+use crate::protocol::{DEFAULT_MINING_FEE, MAX_MINING_FEE};
+
+/// Layout of the config json written by this build.
+/// Bump it with a new entry in [`MIGRATIONS`] whenever a change
+/// would stop older trades from loading as they are
+pub const VERSION: u32 = 1;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<(), Error>;
+
+/// `MIGRATIONS[n]` upgrades a version `n` config to `n + 1`
+const MIGRATIONS: [Migration; VERSION as usize] = [v0_to_v1];
+
+/// Upgrade the config json to [`VERSION`], trades written
+/// before versioning being version 0
+pub fn migrate(mut config: Value) -> Result<Value, Error> {
+    let Some(fields) = config.as_object_mut() else {
+        return Err(Error::Unknown("Trade config is not an object".to_owned()));
+    };
+    let version = match fields.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| Error::Unknown(format!("Invalid trade version {version}")))?,
+    };
+    if version > VERSION {
+        return Err(Error::Unknown(format!(
+            "Trade version {version} is newer than this build, {VERSION}"
+        )));
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(fields)?;
+    }
+    fields.insert("version".to_owned(), VERSION.into());
+
+    Ok(config)
+}
+
+fn object<'a>(
+    fields: &'a mut Map<String, Value>,
+    key: &str,
+) -> Result<&'a mut Map<String, Value>, Error> {
+    match fields.get_mut(key) {
+        Some(Value::Object(object)) => Ok(object),
+        _ => Err(Error::Unknown(format!("Trade {key} is not an object"))),
+    }
+}
+
+/// Version 0 trades predate fee negotiation, pending spends,
+/// xmr sweeping and Alice's monero restore height
+fn v0_to_v1(config: &mut Map<String, Value>) -> Result<(), Error> {
+    let wrapper = object(config, "swap")?;
+    let Some(role) = wrapper.keys().next().cloned() else {
+        return Err(Error::Unknown("Trade swap is empty".to_owned()));
+    };
+    let runner = object(wrapper, &role)?;
+
+    runner.entry("pending_txs").or_insert_with(|| json!([]));
+
+    let swap = object(runner, "swap")?;
+    swap.entry("xmr_recv").or_insert(Value::Null);
+    // the contracts were built with the fixed fee
+    swap.entry("mining_fee")
+        .or_insert_with(|| DEFAULT_MINING_FEE.into());
+    swap.entry("min_mining_fee").or_insert_with(|| 0.into());
+    swap.entry("max_mining_fee")
+        .or_insert_with(|| MAX_MINING_FEE.into());
+
+    // unknown restore heights, the wallet scans from the genesis block
+    if let ("Alice", Some(Value::Object(state))) = (role.as_str(), runner.get_mut("state")) {
+        for (name, value) in state.iter_mut() {
+            match (name.as_str(), value) {
+                ("BchLocked" | "ValidEncSig" | "ProceedPunish", Value::Object(value)) => {
+                    value
+                        .entry("xmr_restore_height")
+                        .or_insert_with(|| 0.into());
+                }
+                // (address, keypair) became (address, keypair, restore_height)
+                ("Refund", Value::Array(value)) if value.len() == 2 => value.push(0.into()),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+
+    use super::{migrate, VERSION};
+    use crate::persist::{decode, encode};
+
+    /// One trade file per historic version and role
+    const FIXTURES: [(&str, &str); 5] = [
+        (
+            "v0-alice-valid-enc-sig",
+            include_str!("fixtures/v0-alice-valid-enc-sig.json"),
+        ),
+        (
+            "v0-alice-refund",
+            include_str!("fixtures/v0-alice-refund.json"),
+        ),
+        (
+            "v0-bob-proceed-refund",
+            include_str!("fixtures/v0-bob-proceed-refund.json"),
+        ),
+        (
+            "v0-bob-swap-success",
+            include_str!("fixtures/v0-bob-swap-success.json"),
+        ),
+        (
+            "v1-alice-valid-enc-sig",
+            include_str!("fixtures/v1-alice-valid-enc-sig.json"),
+        ),
+    ];
+
+    #[test]
+    fn should_load_every_version() {
+        for (name, fixture) in FIXTURES {
+            let config = decode(fixture.as_bytes(), None).expect(name);
+            assert_eq!(config.version, VERSION, "{name}");
+
+            // a migrated trade saves and loads again unchanged
+            let body = encode(&config, None).unwrap();
+            let reloaded = encode(&decode(&body, None).unwrap(), None).unwrap();
+            assert_eq!(body, reloaded, "{name}");
+        }
+    }
+
+    #[test]
+    fn should_migrate_v0() {
+        let (_, v0) = FIXTURES[0];
+        let (_, v1) = FIXTURES[4];
+        let v0 = migrate(serde_json::from_str(v0).unwrap()).unwrap();
+        let v1: Value = serde_json::from_str(v1).unwrap();
+        assert_eq!(v0, v1);
+    }
+
+    #[test]
+    fn should_reject_newer_version() {
+        let (_, v1) = FIXTURES[4];
+        let mut config: Value = serde_json::from_str(v1).unwrap();
+        config["version"] = (VERSION + 1).into();
+        assert!(migrate(config).is_err());
+    }
+}
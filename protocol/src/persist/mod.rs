@@ -0,0 +1,124 @@
+use std::{any::Any, sync::OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Outcome, SwapWrapper};
+
+use envelope::Envelope;
+pub use envelope::Keyring;
+pub use file::FileStore;
+pub use migrate::VERSION;
+pub use sqlite::SqliteStore;
+
+pub mod envelope;
+pub mod file;
+pub mod migrate;
+pub mod sqlite;
+
+static KEYRING: OnceLock<Keyring> = OnceLock::new();
+
+/// Encrypt trades with `passphrase` from now on.
+/// Plain trades and their backups are still read, and encrypted on their next save
+pub fn set_passphrase(passphrase: &str) {
+    if KEYRING.set(Keyring::new(passphrase)).is_err() {
+        eprintln!("Trade passphrase already set");
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NotFound,
+    Unknown(String),
+}
+
+impl<T: ToString> From<T> for Error {
+    fn from(value: T) -> Self {
+        Error::Unknown(value.to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Config {
+    /// Layout of this json, see [`migrate`]
+    pub version: u32,
+    pub swap: SwapWrapper,
+    pub refund_private_key: bitcoincash::PrivateKey,
+    /// Set when the trade is archived
+    #[serde(default)]
+    pub completion: Option<Completion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Completion {
+    pub outcome: Outcome,
+    /// Unix time
+    pub completed_at: u64,
+}
+
+/// A trade loaded from a [`SwapStore`].
+/// Other loads of the same trade wait until this is dropped
+pub struct TradePersist {
+    pub trade_id: String,
+    pub config: Config,
+    _lock: Box<dyn Any + Send + Sync>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TradeInfo {
+    pub trade_id: String,
+    /// As given by [`SwapWrapper::state`]
+    pub state: String,
+}
+
+/// Where the ongoing trades live, and the finished ones are archived
+#[async_trait::async_trait]
+pub trait SwapStore: Send + Sync {
+    /// Store a new trade, fails if the id is taken
+    async fn create(&self, trade_id: &str, config: Config) -> Result<(), Error>;
+
+    /// The trades not archived yet
+    async fn list(&self) -> Result<Vec<TradeInfo>, Error>;
+
+    /// The trades not archived yet and currently in `state`
+    async fn query(&self, state: &str) -> Result<Vec<TradeInfo>, Error> {
+        let mut trades = self.list().await?;
+        trades.retain(|trade| trade.state == state);
+        Ok(trades)
+    }
+
+    /// Wait for the trade lock, then read it
+    async fn load(&self, trade_id: &str) -> Result<TradePersist, Error>;
+
+    async fn save(&self, trade: &TradePersist) -> Result<(), Error>;
+
+    /// Save the trade one last time and stop listing it
+    async fn archive(&self, trade: TradePersist) -> Result<(), Error>;
+
+    /// Open every trade, archived ones included, with `old` and seal it with `new`.
+    /// `None` stands for plain json
+    async fn reencrypt(&self, old: Option<&Keyring>, new: Option<&Keyring>) -> Result<(), Error>;
+}
+
+/// The config json, sealed in an [`Envelope`] once a passphrase is set
+fn encode(config: &Config, keyring: Option<&Keyring>) -> Result<Vec<u8>, Error> {
+    let body = serde_json::to_vec_pretty(config)?;
+    match keyring {
+        None => Ok(body),
+        Some(keyring) => Ok(serde_json::to_vec_pretty(&keyring.seal(&body)?)?),
+    }
+}
+
+/// The config json, opened and migrated to the current [`VERSION`]
+fn decode(body: &[u8], keyring: Option<&Keyring>) -> Result<Config, Error> {
+    let config = match (Envelope::parse(body)?, keyring) {
+        (None, _) => serde_json::from_slice(body)?,
+        (Some(envelope), Some(keyring)) => serde_json::from_slice(&keyring.open(&envelope)?)?,
+        (Some(_), None) => {
+            return Err(Error::Unknown(
+                "Trade is encrypted, no passphrase set".to_owned(),
+            ))
+        }
+    };
+
+    Ok(serde_json::from_value(migrate::migrate(config)?)?)
+}
@@ -0,0 +1,346 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::{
+    sync::{Mutex as AsyncMutex, OwnedMutexGuard},
+    task,
+};
+
+use super::{decode, encode, Config, Error, Keyring, SwapStore, TradeInfo, TradePersist, KEYRING};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS trades (
+        trade_id TEXT PRIMARY KEY,
+        state TEXT NOT NULL,
+        archived INTEGER NOT NULL DEFAULT 0,
+        config BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS trades_state ON trades (archived, state);
+";
+
+/// All trades in one SQLite database, indexed by state.
+/// The config column is the same json as in a trade file.
+///
+/// Trades are only locked against loads from this process
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+    locks: Arc<Mutex<Locks>>,
+}
+
+type Locks = HashMap<String, Arc<AsyncMutex<()>>>;
+
+/// Lock of a loaded trade, its entry is dropped once nobody holds or waits for it
+struct TradeLock {
+    trade_id: String,
+    locks: Arc<Mutex<Locks>>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for TradeLock {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        drop(self.guard.take());
+        if locks
+            .get(&self.trade_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.trade_id);
+        }
+    }
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(SqliteStore {
+            connection: Arc::new(Mutex::new(connection)),
+            locks: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Run `query` on the connection, off the async workers
+    async fn call<T, F>(&self, query: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        let result = task::spawn_blocking(move || {
+            query(&connection.lock().unwrap_or_else(PoisonError::into_inner))
+        })
+        .await?;
+
+        Ok(result?)
+    }
+
+    async fn lock(&self, trade_id: &str) -> TradeLock {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+            locks.entry(trade_id.to_owned()).or_default().clone()
+        };
+
+        TradeLock {
+            trade_id: trade_id.to_owned(),
+            locks: self.locks.clone(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+fn trade_info(row: &rusqlite::Row) -> rusqlite::Result<TradeInfo> {
+    Ok(TradeInfo {
+        trade_id: row.get(0)?,
+        state: row.get(1)?,
+    })
+}
+
+#[async_trait::async_trait]
+impl SwapStore for SqliteStore {
+    async fn create(&self, trade_id: &str, config: Config) -> Result<(), Error> {
+        let trade_id = trade_id.to_owned();
+        let state = config.swap.state();
+        let body = encode(&config, KEYRING.get())?;
+
+        self.call(move |connection| {
+            connection.execute(
+                "INSERT INTO trades (trade_id, state, config) VALUES (?1, ?2, ?3)",
+                params![trade_id, state, body],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<TradeInfo>, Error> {
+        self.call(|connection| {
+            connection
+                .prepare("SELECT trade_id, state FROM trades WHERE archived = 0")?
+                .query_map([], trade_info)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn query(&self, state: &str) -> Result<Vec<TradeInfo>, Error> {
+        let state = state.to_owned();
+        self.call(move |connection| {
+            connection
+                .prepare("SELECT trade_id, state FROM trades WHERE archived = 0 AND state = ?1")?
+                .query_map([state], trade_info)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn load(&self, trade_id: &str) -> Result<TradePersist, Error> {
+        let lock = self.lock(trade_id).await;
+
+        let id = trade_id.to_owned();
+        let body: Option<Vec<u8>> = self
+            .call(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT config FROM trades WHERE trade_id = ?1 AND archived = 0",
+                        [id],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await?;
+        // dropping the lock forgets the unknown id
+        let Some(body) = body else {
+            return Err(Error::NotFound);
+        };
+
+        Ok(TradePersist {
+            trade_id: trade_id.to_owned(),
+            config: decode(&body, KEYRING.get())?,
+            _lock: Box::new(lock),
+        })
+    }
+
+    async fn save(&self, trade: &TradePersist) -> Result<(), Error> {
+        let trade_id = trade.trade_id.clone();
+        let state = trade.config.swap.state();
+        let body = encode(&trade.config, KEYRING.get())?;
+
+        self.call(move |connection| {
+            connection.execute(
+                "UPDATE trades SET state = ?2, config = ?3 WHERE trade_id = ?1",
+                params![trade_id, state, body],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn archive(&self, trade: TradePersist) -> Result<(), Error> {
+        let trade_id = trade.trade_id.clone();
+        let state = trade.config.swap.state();
+        let body = encode(&trade.config, KEYRING.get())?;
+
+        self.call(move |connection| {
+            connection.execute(
+                "UPDATE trades SET state = ?2, config = ?3, archived = 1 WHERE trade_id = ?1",
+                params![trade_id, state, body],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn reencrypt(&self, old: Option<&Keyring>, new: Option<&Keyring>) -> Result<(), Error> {
+        let trade_ids: Vec<String> = self
+            .call(|connection| {
+                connection
+                    .prepare("SELECT trade_id FROM trades")?
+                    .query_map([], |row| row.get(0))?
+                    .collect()
+            })
+            .await?;
+
+        let mut locks = Vec::with_capacity(trade_ids.len());
+        for trade_id in trade_ids.iter() {
+            locks.push(self.lock(trade_id).await);
+        }
+
+        let rows: Vec<(String, Vec<u8>)> = self
+            .call(|connection| {
+                connection
+                    .prepare("SELECT trade_id, config FROM trades")?
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect()
+            })
+            .await?;
+
+        // every trade is opened before one is written, a wrong passphrase changes nothing
+        let mut bodies = Vec::with_capacity(rows.len());
+        for (trade_id, body) in rows {
+            let config =
+                decode(&body, old).map_err(|e| Error::Unknown(format!("{trade_id}: {e:?}")))?;
+            bodies.push((trade_id, encode(&config, new)?));
+        }
+
+        self.call(move |connection| {
+            let transaction = connection.unchecked_transaction()?;
+            for (trade_id, body) in bodies {
+                transaction.execute(
+                    "UPDATE trades SET config = ?2 WHERE trade_id = ?1",
+                    params![trade_id, body],
+                )?;
+            }
+            transaction.commit()
+        })
+        .await?;
+
+        drop(locks);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::SqliteStore;
+    use crate::{
+        alice::Alice,
+        keys::{
+            bitcoin::{random_private_key, Network},
+            KeyPrivate,
+        },
+        persist::{envelope::Envelope, Config, Error, Keyring, SwapStore, VERSION},
+        protocol::{Swap, SwapWrapper, MAX_MINING_FEE, MIN_MINING_FEE},
+    };
+
+    fn config() -> Config {
+        let swap = Swap {
+            id: "trade".to_owned(),
+            keys: KeyPrivate::random(Network::Regtest),
+            bch_amount: bitcoincash::Amount::from_sat(100_000),
+            xmr_amount: monero::Amount::from_pico(1_000_000),
+            xmr_network: monero::Network::Mainnet,
+            bch_network: Network::Regtest,
+            bch_recv: bitcoincash::Script::new(),
+            xmr_recv: None,
+            timelock1: 20,
+            timelock2: 20,
+            mining_fee: MIN_MINING_FEE,
+            min_mining_fee: MIN_MINING_FEE,
+            max_mining_fee: MAX_MINING_FEE,
+        };
+
+        Config {
+            version: VERSION,
+            swap: SwapWrapper::Alice(Alice::new(swap)),
+            refund_private_key: random_private_key(Network::Regtest),
+            completion: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_store_trades() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store.create("trade", config()).await.unwrap();
+        assert!(store.create("trade", config()).await.is_err());
+
+        let trade = store.load("trade").await.unwrap();
+        store.save(&trade).await.unwrap();
+        // locked until dropped
+        let load = tokio::time::timeout(Duration::from_millis(100), store.load("trade"));
+        assert!(load.await.is_err());
+
+        assert_eq!(store.list().await.unwrap().len(), 1);
+        assert_eq!(store.query("Init").await.unwrap().len(), 1);
+        assert!(store.query("Refund").await.unwrap().is_empty());
+
+        store.archive(trade).await.unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        assert!(matches!(store.load("trade").await, Err(Error::NotFound)));
+        // unknown and released trades keep no lock around
+        assert!(store.locks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_reencrypt_trades() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store.create("trade", config()).await.unwrap();
+        store
+            .archive(store.load("trade").await.unwrap())
+            .await
+            .unwrap();
+        store.create("other", config()).await.unwrap();
+
+        let bodies = || {
+            store.call(|connection| {
+                connection
+                    .prepare("SELECT config FROM trades")?
+                    .query_map([], |row| row.get::<_, Vec<u8>>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+        };
+        let keyring = Keyring::new("passphrase");
+        store.reencrypt(None, Some(&keyring)).await.unwrap();
+        for body in bodies().await.unwrap() {
+            assert!(Envelope::parse(&body).unwrap().is_some());
+        }
+        assert!(store.load("other").await.is_err());
+
+        // a wrong passphrase leaves every trade as it was
+        let wrong = Keyring::new("wrong");
+        let sealed = bodies().await.unwrap();
+        assert!(store.reencrypt(Some(&wrong), None).await.is_err());
+        assert_eq!(bodies().await.unwrap(), sealed);
+
+        store.reencrypt(Some(&keyring), None).await.unwrap();
+        assert!(store.load("other").await.is_ok());
+    }
+}
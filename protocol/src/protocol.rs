@@ -7,10 +7,26 @@ use serde::{Deserialize, Serialize};
 use crate::{
     alice::Alice,
     bob::Bob,
+    contract::timelock::Confirmation,
     keys::{bitcoin, KeyPublic},
     utils::{bch_amount, monero_amount, monero_network},
 };
 
+/// Mining fee in sats used by each contract spend, unless estimated
+pub const DEFAULT_MINING_FEE: u64 = 1000;
+pub const MIN_MINING_FEE: u64 = 500;
+pub const MAX_MINING_FEE: u64 = 20_000;
+
+const DUST_LIMIT: u64 = 546;
+
+fn default_mining_fee() -> u64 {
+    DEFAULT_MINING_FEE
+}
+
+fn default_max_mining_fee() -> u64 {
+    MAX_MINING_FEE
+}
+
 #[derive(Debug)]
 pub enum Error {
     InvalidProof,
@@ -21,6 +37,7 @@ pub enum Error {
     InvalidSignature,
     InvalidXmrAmount,
     InvalidTimelock,
+    InvalidMiningFee,
 }
 
 impl fmt::Display for Error {
@@ -29,8 +46,22 @@ impl fmt::Display for Error {
     }
 }
 
+/// How a trade ended, once nothing is left to do for it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    /// Both sides received what they traded for
+    Success,
+    /// Bob took the bch back, alice kept or swept her xmr
+    Refunded,
+    /// Bob did not claim his refund before timelock2, alice took the bch
+    Punished,
+    /// Dropped before anything was locked
+    Aborted(String),
+}
+
 #[derive(Debug)]
 pub enum Action {
+    /// Nothing is locked yet, the trade can be dropped
     SafeDelete,
     /// No further transition needed
     TradeSuccess,
@@ -47,9 +78,18 @@ pub enum Action {
 
     WatchXmr(monero::Address),
     CreateXmrView(monero::ViewPair),
+    /// Restore a spend-capable wallet from the shared keys
+    /// and send everything to destination
+    SweepXmr {
+        keypair: monero::KeyPair,
+        restore_height: u64,
+        destination: monero::Address,
+    },
 
     UnlockBchNormal,
     UnlockBchFallback,
+    /// Spend the refund contract to alice after timelock2
+    UnlockBchPunish,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -57,6 +97,8 @@ pub enum Transition {
     Msg0 {
         keys: KeyPublic,
         receiving: bitcoincash::Script,
+        #[serde(default = "default_mining_fee")]
+        mining_fee: u64,
     },
     Contract {
         bch_address: String,
@@ -66,12 +108,20 @@ pub enum Transition {
     EncSig(EncryptedSignature),
     DecSig(Signature),
 
-    /// You are responsible to only use on confirmed tx
+    /// You are responsible to only use on confirmed tx,
+    /// e.g. from `ScriptWatcher` which checks it against the header chain
+    /// and finds when its contract output unlocks
+    #[serde(skip)]
+    BchConfirmedTx(bitcoincash::Transaction, Confirmation),
+    /// Unconfirmed spend of a contract. Only used when it reveals
+    /// the other side key, anything else waits for BchConfirmedTx
     #[serde(skip)]
-    BchConfirmedTx(bitcoincash::Transaction, u32), // TODO: u32==confirmation. is it really u32?
+    BchMempoolTx(bitcoincash::Transaction),
     XmrLockVerified(#[serde(with = "monero_amount")] monero::Amount),
 
     SetXmrRestoreHeight(u64),
+    XmrLocked(XmrLockTx),
+    XmrSwept(Vec<String>),
 }
 
 impl Display for Transition {
@@ -82,12 +132,23 @@ impl Display for Transition {
             Transition::EncSig(_) => write!(f, "Transition::EncSig"),
             Transition::DecSig(_) => write!(f, "Transition::DecSig"),
             Transition::BchConfirmedTx(_, _) => write!(f, "Transition::BchConfirmedTx"),
+            Transition::BchMempoolTx(_) => write!(f, "Transition::BchMempoolTx"),
             Transition::XmrLockVerified(_) => write!(f, "Transition::XmrLockVerified"),
             Transition::SetXmrRestoreHeight(_) => write!(f, "Transition::SetXmrRestoreHeight"),
+            Transition::XmrLocked(_) => write!(f, "Transition::XmrLocked"),
+            Transition::XmrSwept(_) => write!(f, "Transition::XmrSwept"),
         }
     }
 }
 
+/// Proof that the xmr was sent to the shared address.
+/// The tx key lets anyone verify the transfer with `check_tx_key`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct XmrLockTx {
+    pub tx_hash: String,
+    pub tx_key: String,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Swap {
     pub id: String,
@@ -97,6 +158,9 @@ pub struct Swap {
 
     pub keys: crate::keys::KeyPrivate,
     pub bch_recv: bitcoincash::Script,
+    /// Where to sweep the xmr once we hold the shared spend key
+    #[serde(default)]
+    pub xmr_recv: Option<monero::Address>,
 
     #[serde(with = "monero_amount")]
     pub xmr_amount: monero::Amount,
@@ -105,6 +169,34 @@ pub struct Swap {
 
     pub timelock1: u32,
     pub timelock2: u32,
+
+    /// Sats paid by each contract spend.
+    /// Our proposal until Msg0, then the agreed fee
+    #[serde(default = "default_mining_fee")]
+    pub mining_fee: u64,
+    #[serde(default)]
+    pub min_mining_fee: u64,
+    #[serde(default = "default_max_mining_fee")]
+    pub max_mining_fee: u64,
+}
+
+impl Swap {
+    /// The higher of both proposals wins, so each side can
+    /// agree on the same contract without another round trip.
+    /// Returns None if it is outside our limits
+    pub fn negotiate_mining_fee(&self, proposed: u64) -> Option<u64> {
+        let mining_fee = self.mining_fee.max(proposed);
+        if mining_fee < self.min_mining_fee || mining_fee > self.max_mining_fee {
+            return None;
+        }
+
+        // The refund path pays the fee twice and must leave a spendable output
+        if mining_fee * 2 + DUST_LIMIT > self.bch_amount.to_sat() {
+            return None;
+        }
+
+        Some(mining_fee)
+    }
 }
 
 impl Debug for Swap {
@@ -121,8 +213,10 @@ impl Debug for Swap {
                     \t\tves: bitcoincash::PrivateKey({}),\n\
                 \t}},\n\
                 \tbch_recv: {:?},\n\
+                \txmr_recv: {:?},\n\
                 \txmr_amount: {:?},\n\
                 \tbch_amount: {:?},\n\
+                \tmining_fee: {:?},\n\
             }}\n\
             ",
             self.id,
@@ -132,8 +226,10 @@ impl Debug for Swap {
             self.keys.monero_view,
             self.keys.ves,
             self.bch_recv,
+            self.xmr_recv,
             self.xmr_amount,
             self.bch_amount,
+            self.mining_fee,
         )
     }
 }
@@ -154,3 +250,195 @@ pub enum SwapWrapper {
     Alice(Alice),
     Bob(Bob),
 }
+
+impl SwapWrapper {
+    /// Name of the current state, e.g. `BchLocked`
+    pub fn state(&self) -> &'static str {
+        match self {
+            SwapWrapper::Alice(alice) => alice.state.name(),
+            SwapWrapper::Bob(bob) => bob.state.name(),
+        }
+    }
+
+    pub fn outcome(&self) -> Option<Outcome> {
+        match self {
+            SwapWrapper::Alice(alice) => alice.outcome(),
+            SwapWrapper::Bob(bob) => bob.outcome(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoincash::{PackedLockTime, Script, Transaction, TxIn, TxOut};
+
+    use super::{
+        Outcome, Swap, SwapEvents, Transition, DEFAULT_MINING_FEE, MAX_MINING_FEE, MIN_MINING_FEE,
+    };
+    use crate::{
+        alice::{self, Alice},
+        bob::{self, Bob},
+        contract::timelock::Confirmation,
+        keys::{
+            bitcoin::{random_private_key, Network},
+            KeyPrivate,
+        },
+        wallet::BchWallet,
+    };
+
+    const UNLOCKED: Confirmation = Confirmation {
+        height: 1,
+        tip: 100,
+        unlock_height: Some(1),
+    };
+
+    fn swap() -> Swap {
+        let recv = BchWallet::new(random_private_key(Network::Regtest), Network::Regtest);
+        Swap {
+            id: "trade".to_owned(),
+            keys: KeyPrivate::random(Network::Regtest),
+            bch_amount: bitcoincash::Amount::from_sat(100_000),
+            xmr_amount: monero::Amount::from_pico(1_000_000),
+            xmr_network: monero::Network::Mainnet,
+            bch_network: Network::Regtest,
+            bch_recv: recv.script(),
+            xmr_recv: None,
+            timelock1: 20,
+            timelock2: 20,
+            mining_fee: DEFAULT_MINING_FEE,
+            min_mining_fee: MIN_MINING_FEE,
+            max_mining_fee: MAX_MINING_FEE,
+        }
+    }
+
+    fn step<T: SwapEvents<State = T>>(swap: T, transition: Transition) -> T {
+        let (swap, _, error) = swap.transition(transition);
+        assert!(error.is_none(), "{error:?}");
+        swap
+    }
+
+    /// Run both sides until bob is about to fund the swaplock
+    fn negotiate() -> (Alice, Bob) {
+        let (mut alice, mut bob) = (Alice::new(swap()), Bob::new(swap()));
+
+        bob = step(bob, alice.get_transition().unwrap());
+        alice = step(alice, bob.get_transition().unwrap());
+        bob = step(bob, alice.get_transition().unwrap());
+        alice = step(alice, bob.get_transition().unwrap());
+        bob = step(bob, alice.get_transition().unwrap());
+        assert!(matches!(bob.state, bob::State::VerifiedEncSig(_)));
+
+        (alice, bob)
+    }
+
+    fn fund_swaplock(bob: &Bob) -> Transaction {
+        let swaplock = bob.get_contract_pair().unwrap().swaplock.locking_script();
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: bob.swap.bch_amount.to_sat(),
+                script_pubkey: Script::from(swaplock),
+                token: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn should_negotiate_mining_fee() {
+        let swap = swap();
+        // the higher proposal wins
+        assert_eq!(swap.negotiate_mining_fee(800), Some(DEFAULT_MINING_FEE));
+        assert_eq!(swap.negotiate_mining_fee(3000), Some(3000));
+        assert_eq!(swap.negotiate_mining_fee(MAX_MINING_FEE + 1), None);
+
+        let swap = Swap {
+            mining_fee: 1000,
+            min_mining_fee: 2000,
+            ..swap
+        };
+        assert_eq!(swap.negotiate_mining_fee(1500), None);
+    }
+
+    #[test]
+    fn should_reject_dust_mining_fee() {
+        let swap = Swap {
+            bch_amount: bitcoincash::Amount::from_sat(3000),
+            ..swap()
+        };
+        // the refund output must stay above the dust limit
+        assert_eq!(swap.negotiate_mining_fee(1200), Some(1200));
+        assert_eq!(swap.negotiate_mining_fee(1300), None);
+    }
+
+    #[test]
+    fn should_end_in_success() {
+        let (mut alice, mut bob) = negotiate();
+        let fund = fund_swaplock(&bob);
+        alice = step(
+            alice,
+            Transition::BchConfirmedTx(fund, Confirmation::default()),
+        );
+        let xmr_amount = bob.swap.xmr_amount;
+        bob = step(bob, Transition::XmrLockVerified(xmr_amount));
+
+        alice = step(alice, bob.get_transition().unwrap());
+        let unlock = alice.get_unlock_normal_tx().unwrap();
+        assert_eq!(alice.outcome(), None);
+
+        let confirmed = Transition::BchConfirmedTx(unlock.clone(), Confirmation::default());
+        alice = step(alice, confirmed);
+        assert!(matches!(alice.state, alice::State::SwapSuccess));
+        assert_eq!(alice.outcome(), Some(Outcome::Success));
+
+        bob = step(
+            bob,
+            Transition::BchConfirmedTx(unlock, Confirmation::default()),
+        );
+        assert_eq!(bob.outcome(), Some(Outcome::Success));
+    }
+
+    #[test]
+    fn should_end_in_refund() {
+        let (mut alice, mut bob) = negotiate();
+        let fund = fund_swaplock(&bob);
+        alice = step(alice, Transition::BchConfirmedTx(fund.clone(), UNLOCKED));
+        bob = step(bob, Transition::BchConfirmedTx(fund, UNLOCKED));
+        let (to_refund, to_bob) = bob.refund().unwrap();
+
+        let mined = Confirmation::default();
+        alice = step(alice, Transition::BchConfirmedTx(to_refund.clone(), mined));
+        bob = step(bob, Transition::BchConfirmedTx(to_refund, UNLOCKED));
+        assert_eq!(bob.outcome(), None);
+        bob = step(bob, Transition::BchConfirmedTx(to_bob.clone(), UNLOCKED));
+        assert!(matches!(bob.state, bob::State::Refunded));
+        assert_eq!(bob.outcome(), Some(Outcome::Refunded));
+
+        // ongoing until the xmr is swept
+        alice = step(alice, Transition::BchConfirmedTx(to_bob, mined));
+        assert!(matches!(alice.state, alice::State::Refund(..)));
+        assert_eq!(alice.outcome(), None);
+        alice = step(alice, Transition::XmrSwept(Vec::new()));
+        assert_eq!(alice.outcome(), Some(Outcome::Refunded));
+    }
+
+    #[test]
+    fn should_end_in_punish() {
+        let (mut alice, mut bob) = negotiate();
+        let fund = fund_swaplock(&bob);
+        alice = step(alice, Transition::BchConfirmedTx(fund.clone(), UNLOCKED));
+        bob = step(bob, Transition::BchConfirmedTx(fund, UNLOCKED));
+
+        let (to_refund, _) = bob.refund().unwrap();
+        alice = step(alice, Transition::BchConfirmedTx(to_refund, UNLOCKED));
+        let to_alice = alice.get_refund_to_alice_tx().unwrap();
+
+        bob = step(bob, Transition::BchConfirmedTx(to_alice.clone(), UNLOCKED));
+        assert!(matches!(bob.state, bob::State::Punished));
+        assert_eq!(bob.outcome(), Some(Outcome::Punished));
+
+        alice = step(alice, Transition::BchConfirmedTx(to_alice, UNLOCKED));
+        assert_eq!(alice.outcome(), Some(Outcome::Punished));
+    }
+}
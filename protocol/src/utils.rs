@@ -172,6 +172,29 @@ pub mod bch_amount {
     }
 }
 
+pub mod bch_transaction {
+    use bitcoincash::consensus;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    type Type = bitcoincash::Transaction;
+
+    pub fn serialize<S>(transaction: &Type, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_str(&hex::encode(consensus::serialize(transaction)))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Type, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let string = String::deserialize(deserializer)?;
+        let bytes = hex::decode(string).map_err(|err| Error::custom(err.to_string()))?;
+        consensus::deserialize(&bytes).map_err(|err| Error::custom(err.to_string()))
+    }
+}
+
 pub mod monero_public_key {
     use std::str::FromStr;
 
@@ -0,0 +1,470 @@
+use std::collections::HashSet;
+
+use anyhow::bail;
+use bitcoincash::{
+    blockdata::script::Builder,
+    consensus::Encodable,
+    hashes::{sha256d, Hash, HashEngine},
+    secp256k1::{Message, Secp256k1},
+    Amount, OutPoint, PackedLockTime, PrivateKey, PublicKey, Script, Sequence, Transaction, TxIn,
+    TxOut, Txid,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use crate::{
+    blockchain::{self, electrum::ElectrumError, TcpElectrum},
+    keys::bitcoin::{address, Network},
+};
+
+const SIGHASH_ALL_FORKID: u32 = 0x41;
+const DUST_LIMIT: u64 = 546;
+
+// Serialized sizes used for fee estimation
+const TX_OVERHEAD_SIZE: u64 = 10;
+const P2PKH_INPUT_SIZE: u64 = 148;
+const P2PKH_OUTPUT_SIZE: u64 = 34;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Utxo {
+    pub tx_hash: Txid,
+    pub tx_pos: u32,
+    pub height: u64,
+    pub value: u64,
+    /// CashTokens held by the coin. Spending it as plain bch would burn them
+    #[serde(default)]
+    pub token_data: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct ListUnspent {
+    result: Vec<Utxo>,
+}
+
+/// P2PKH hot wallet used to fund the swaplock contract
+pub struct BchWallet {
+    private_key: PrivateKey,
+    network: Network,
+    pub sats_per_byte: u64,
+
+    // Reserved by a tx being built or sent. Electrum can still
+    // report these as unspent until our tx reach the mempool
+    spent: Mutex<HashSet<OutPoint>>,
+}
+
+impl BchWallet {
+    pub fn new(private_key: PrivateKey, network: Network) -> Self {
+        BchWallet {
+            private_key,
+            network,
+            sats_per_byte: 1,
+            spent: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let secp = Secp256k1::signing_only();
+        self.private_key.public_key(&secp)
+    }
+
+    pub fn script(&self) -> Script {
+        Script::new_p2pkh(&self.public_key().pubkey_hash())
+    }
+
+    pub fn cash_address(&self) -> String {
+        let hash = self.public_key().pubkey_hash();
+        match self.network {
+            Network::Mainnet => address::encode(&hash, "bitcoincash", 0),
+            Network::Testnet => address::encode(&hash, "bchtest", 0),
+            Network::Regtest => address::encode(&hash, "bchreg", 0),
+        }
+    }
+
+    async fn list_unspent(&self, bch_server: &TcpElectrum) -> anyhow::Result<Vec<Utxo>> {
+        let response = bch_server
+            .send(
+                "blockchain.address.listunspent",
+                json!([self.cash_address()]),
+            )
+            .await?;
+        Ok(serde_json::from_str::<ListUnspent>(&response)?.result)
+    }
+
+    pub async fn get_utxos(&self, bch_server: &TcpElectrum) -> anyhow::Result<Vec<Utxo>> {
+        let utxos = self.list_unspent(bch_server).await?;
+        let spent = self.spent.lock().await;
+        Ok(unspent(utxos, &spent))
+    }
+
+    /// Give back coins reserved by a tx that was not broadcast
+    pub async fn release(&self, utxos: &[Utxo]) {
+        let mut spent = self.spent.lock().await;
+        for utxo in utxos {
+            spent.remove(&OutPoint::new(utxo.tx_hash, utxo.tx_pos));
+        }
+    }
+
+    /// Build and sign a transaction sending `amount` to `script_pubkey`.
+    /// Change goes back to the wallet.
+    ///
+    /// The selected coins are reserved, so concurrent calls pick others,
+    /// until given back with [`BchWallet::release`]
+    pub async fn create_tx(
+        &self,
+        bch_server: &TcpElectrum,
+        script_pubkey: Script,
+        amount: Amount,
+    ) -> anyhow::Result<(Transaction, Vec<Utxo>)> {
+        let utxos = self.list_unspent(bch_server).await?;
+        let mut spent = self.spent.lock().await;
+        let mut utxos = unspent(utxos, &spent);
+        // spend confirmed and bigger coins first
+        utxos.sort_by_key(|utxo| (utxo.height == 0, u64::MAX - utxo.value));
+
+        let amount = amount.to_sat();
+        let mut selected = Vec::new();
+        let mut total = 0;
+        let mut fee = 0;
+        for utxo in utxos {
+            total += utxo.value;
+            selected.push(utxo);
+
+            let size = TX_OVERHEAD_SIZE
+                + (selected.len() as u64 * P2PKH_INPUT_SIZE)
+                + (2 * P2PKH_OUTPUT_SIZE);
+            fee = size * self.sats_per_byte;
+            if total >= amount + fee {
+                break;
+            }
+        }
+
+        if total < amount + fee {
+            bail!(
+                "Insufficient funds on {}. Need {} sats, have {} sats",
+                self.cash_address(),
+                amount + fee,
+                total
+            );
+        }
+
+        let mut output = vec![TxOut {
+            value: amount,
+            script_pubkey,
+            token: None,
+        }];
+
+        for utxo in selected.iter() {
+            spent.insert(OutPoint::new(utxo.tx_hash, utxo.tx_pos));
+        }
+        drop(spent);
+
+        let change = total - amount - fee;
+        if change >= DUST_LIMIT {
+            output.push(TxOut {
+                value: change,
+                script_pubkey: self.script(),
+                token: None,
+            });
+        }
+
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: selected
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: OutPoint::new(utxo.tx_hash, utxo.tx_pos),
+                    sequence: Sequence(0xffffffff),
+                    ..Default::default()
+                })
+                .collect(),
+            output,
+        };
+
+        if let Err(e) = self.sign(&mut transaction, &selected) {
+            self.release(&selected).await;
+            return Err(e);
+        }
+        Ok((transaction, selected))
+    }
+
+    fn sign(&self, transaction: &mut Transaction, utxos: &[Utxo]) -> anyhow::Result<()> {
+        let secp = Secp256k1::signing_only();
+        let script_code = self.script();
+        let pubkey = self.public_key();
+
+        let mut script_sigs = Vec::new();
+        for (index, utxo) in utxos.iter().enumerate() {
+            let sighash = signature_hash(
+                transaction,
+                index,
+                &script_code,
+                utxo.value,
+                SIGHASH_ALL_FORKID,
+            )?;
+            let message = Message::from_slice(&sighash[..])?;
+            let signature = secp.sign_ecdsa(&message, &self.private_key.inner);
+
+            let mut signature = signature.serialize_der().to_vec();
+            signature.push(SIGHASH_ALL_FORKID as u8);
+
+            script_sigs.push(
+                Builder::new()
+                    .push_slice(&signature)
+                    .push_key(&pubkey)
+                    .into_script(),
+            );
+        }
+
+        for (input, script_sig) in transaction.input.iter_mut().zip(script_sigs) {
+            input.script_sig = script_sig;
+        }
+
+        Ok(())
+    }
+
+    /// Give back the coins of a tx whose broadcast failed, unless the
+    /// server has it anyway, e.g. the connection dropped after sending it
+    async fn release_unknown(&self, bch_server: &TcpElectrum, txid: &Txid, utxos: &[Utxo]) {
+        if let Err(ElectrumError::Rpc { .. }) = bch_server.get_transaction(txid).await {
+            self.release(utxos).await;
+        }
+    }
+
+    /// Send `amount` to `script_pubkey` and broadcast it
+    pub async fn send(
+        &self,
+        bch_server: &TcpElectrum,
+        script_pubkey: Script,
+        amount: Amount,
+    ) -> anyhow::Result<Txid> {
+        let (transaction, utxos) = self.create_tx(bch_server, script_pubkey, amount).await?;
+        match bch_server.broadcast(&transaction).await {
+            Ok(txid) => Ok(txid),
+            Err(e) => {
+                self.release_unknown(bch_server, &transaction.txid(), &utxos)
+                    .await;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Spend our output of the unconfirmed `parent` back to us, with a fee
+    /// bringing the whole package to the estimated rate (CPFP).
+    /// The package is `parent` and its unconfirmed ancestors
+    pub async fn pay_for(
+        &self,
+        bch_server: &TcpElectrum,
+        parent: &Transaction,
+        package_size: u64,
+        package_fee: u64,
+    ) -> anyhow::Result<Txid> {
+        let script_pubkey = self.script();
+        let Some((vout, output)) = parent
+            .output
+            .iter()
+            .enumerate()
+            .find(|(_, output)| output.script_pubkey == script_pubkey)
+        else {
+            bail!("{} pays nothing to {}", parent.txid(), self.cash_address());
+        };
+
+        let sats_per_byte = self
+            .sats_per_byte
+            .max(blockchain::estimate_fee_rate(bch_server, 1).await?);
+        let size = TX_OVERHEAD_SIZE + P2PKH_INPUT_SIZE + P2PKH_OUTPUT_SIZE;
+        let fee = ((package_size + size) * sats_per_byte)
+            .saturating_sub(package_fee)
+            .max(size * sats_per_byte);
+        if output.value < fee + DUST_LIMIT {
+            bail!("Output of {} too small to pay {fee} sats", parent.txid());
+        }
+
+        let utxo = Utxo {
+            tx_hash: parent.txid(),
+            tx_pos: vout as u32,
+            height: 0,
+            value: output.value,
+            token_data: None,
+        };
+        let outpoint = OutPoint::new(utxo.tx_hash, utxo.tx_pos);
+        if !self.spent.lock().await.insert(outpoint) {
+            bail!("Output of {} is already being spent", parent.txid());
+        }
+
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: outpoint,
+                sequence: Sequence(0xffffffff),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: output.value - fee,
+                script_pubkey,
+                token: None,
+            }],
+        };
+
+        let utxos = [utxo];
+        if let Err(e) = self.sign(&mut transaction, &utxos) {
+            self.release(&utxos).await;
+            return Err(e);
+        }
+        match bch_server.broadcast(&transaction).await {
+            Ok(txid) => Ok(txid),
+            Err(e) => {
+                self.release_unknown(bch_server, &transaction.txid(), &utxos)
+                    .await;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Plain bch coins not reserved by another tx
+fn unspent(utxos: Vec<Utxo>, spent: &HashSet<OutPoint>) -> Vec<Utxo> {
+    utxos
+        .into_iter()
+        .filter(|utxo| utxo.token_data.is_none())
+        .filter(|utxo| !spent.contains(&OutPoint::new(utxo.tx_hash, utxo.tx_pos)))
+        .collect()
+}
+
+/// BIP143 digest, as used by bch with SIGHASH_ALL | SIGHASH_FORKID
+fn signature_hash(
+    transaction: &Transaction,
+    input_index: usize,
+    script_code: &Script,
+    value: u64,
+    sighash_type: u32,
+) -> anyhow::Result<sha256d::Hash> {
+    let mut prevouts = sha256d::Hash::engine();
+    let mut sequences = sha256d::Hash::engine();
+    for input in transaction.input.iter() {
+        input.previous_output.consensus_encode(&mut prevouts)?;
+        input.sequence.consensus_encode(&mut sequences)?;
+    }
+
+    let mut outputs = sha256d::Hash::engine();
+    for output in transaction.output.iter() {
+        output.consensus_encode(&mut outputs)?;
+    }
+
+    let input = &transaction.input[input_index];
+    let mut engine = sha256d::Hash::engine();
+    transaction.version.consensus_encode(&mut engine)?;
+    engine.input(&sha256d::Hash::from_engine(prevouts)[..]);
+    engine.input(&sha256d::Hash::from_engine(sequences)[..]);
+    input.previous_output.consensus_encode(&mut engine)?;
+    script_code.consensus_encode(&mut engine)?;
+    value.consensus_encode(&mut engine)?;
+    input.sequence.consensus_encode(&mut engine)?;
+    engine.input(&sha256d::Hash::from_engine(outputs)[..]);
+    transaction.lock_time.consensus_encode(&mut engine)?;
+    sighash_type.consensus_encode(&mut engine)?;
+
+    Ok(sha256d::Hash::from_engine(engine))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoincash::{
+        blockdata::script::Instruction,
+        consensus::deserialize,
+        hashes::{sha256d, Hash},
+        secp256k1::{ecdsa::Signature, Message, Secp256k1},
+        OutPoint, PackedLockTime, Script, Transaction, TxIn, Txid,
+    };
+    use hex_literal::hex;
+
+    use std::collections::HashSet;
+
+    use super::{signature_hash, unspent, BchWallet, ListUnspent, Utxo, SIGHASH_ALL_FORKID};
+    use crate::keys::bitcoin::{random_private_key, Network};
+
+    #[test]
+    fn should_match_bip143_vector() {
+        // Native P2WPKH example of BIP143, second input
+        let transaction: Transaction = deserialize(&hex!(
+            "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000"
+        ))
+        .unwrap();
+        let script_code =
+            Script::from(hex!("76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac").to_vec());
+
+        let sighash = signature_hash(&transaction, 1, &script_code, 600_000_000, 1).unwrap();
+        assert_eq!(
+            sighash[..],
+            hex!("c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670")
+        );
+    }
+
+    #[test]
+    fn should_sign_with_forkid() {
+        let wallet = BchWallet::new(random_private_key(Network::Regtest), Network::Regtest);
+        let utxo = Utxo {
+            tx_hash: Txid::from(sha256d::Hash::hash(b"parent")),
+            tx_pos: 0,
+            height: 1,
+            value: 10_000,
+            token_data: None,
+        };
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(utxo.tx_hash, utxo.tx_pos),
+                ..Default::default()
+            }],
+            output: Vec::new(),
+        };
+        wallet
+            .sign(&mut transaction, std::slice::from_ref(&utxo))
+            .unwrap();
+
+        let mut pushes = transaction.input[0].script_sig.instructions();
+        let Some(Ok(Instruction::PushBytes(signature))) = pushes.next() else {
+            panic!("No signature");
+        };
+        let (hash_type, der) = signature.split_last().unwrap();
+        assert_eq!(*hash_type as u32, SIGHASH_ALL_FORKID);
+
+        let sighash = signature_hash(
+            &transaction,
+            0,
+            &wallet.script(),
+            utxo.value,
+            SIGHASH_ALL_FORKID,
+        )
+        .unwrap();
+        let message = Message::from_slice(&sighash[..]).unwrap();
+        let signature = Signature::from_der(der).unwrap();
+        let secp = Secp256k1::verification_only();
+        assert!(secp
+            .verify_ecdsa(&message, &signature, &wallet.public_key().inner)
+            .is_ok());
+    }
+
+    #[test]
+    fn should_skip_token_utxos() {
+        // Fulcrum listunspent with a plain coin, a fungible token and a reserved coin
+        let response = r#"{"jsonrpc":"2.0","id":1,"result":[
+            {"height":100,"tx_hash":"1111111111111111111111111111111111111111111111111111111111111111","tx_pos":0,"value":10000},
+            {"height":100,"tx_hash":"2222222222222222222222222222222222222222222222222222222222222222","tx_pos":1,"value":1000,"token_data":{"amount":"500","category":"3333333333333333333333333333333333333333333333333333333333333333"}},
+            {"height":0,"tx_hash":"4444444444444444444444444444444444444444444444444444444444444444","tx_pos":2,"value":5000}
+        ]}"#;
+        let utxos = serde_json::from_str::<ListUnspent>(response)
+            .unwrap()
+            .result;
+        assert_eq!(utxos.len(), 3);
+        assert!(utxos[1].token_data.is_some());
+
+        let spent = HashSet::from([OutPoint::new(utxos[2].tx_hash, utxos[2].tx_pos)]);
+        let utxos = unspent(utxos, &spent);
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].value, 10_000);
+    }
+}
@@ -0,0 +1,42 @@
+use protocol::config::NodeConfig;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    #[serde(flatten)]
+    pub node: NodeConfig,
+
+    pub port: u16,
+    /// xmr per bch, read on every quote
+    pub price_file: String,
+    /// Fraction kept on each trade, 0.01 = 1%
+    pub spread: f64,
+    pub min_bch_sats: u64,
+    pub max_bch_sats: u64,
+    pub quote_ttl_secs: u64,
+    /// Directory holding `ongoing/` and `success/`, or `sqlite:<path>`
+    pub trade_store: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            node: NodeConfig::default(),
+            port: 8080,
+            price_file: "./price.txt".to_owned(),
+            spread: 0.01,
+            min_bch_sats: 10_000,
+            max_bch_sats: 10_000_000,
+            quote_ttl_secs: 60,
+            trade_store: "./.trades".to_owned(),
+        }
+    }
+}
+
+pub fn load() -> anyhow::Result<ServerConfig> {
+    let config: ServerConfig = protocol::config::load()?;
+    config.node.check_networks()?;
+    config.node.check_mining_fees()?;
+    Ok(config)
+}
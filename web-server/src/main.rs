@@ -1,156 +1,268 @@
 // #![allow(unused_variables, unused_imports, dead_code)]
-use std::{env, net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::Router;
 use protocol::{
-    alice,
-    blockchain::{self, TcpElectrum},
+    alice, bitcoincash,
+    blockchain::{self, watch::ScriptWatcher, xmr::XmrWallet, TcpElectrum},
     bob,
     keys::bitcoin::Network,
+    lifecycle::Lifecycle,
     monero, monero_rpc,
-    persist::TradePersist,
+    persist::{self, FileStore, SqliteStore, SwapStore},
     protocol::SwapWrapper,
+    wallet::BchWallet,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex},
+    time::sleep,
 };
-use serde_json::json;
-use tokio::{fs, net::TcpStream, sync::Mutex, time::sleep};
 
-use trader::get_file_path;
+use pricing::{FilePriceSource, Pricing};
 
+mod config;
+mod pricing;
 mod trader;
 pub mod utils;
 
 pub struct AppState {
+    store: Box<dyn SwapStore>,
     bch_server: TcpElectrum,
+    watcher: ScriptWatcher,
     monerod: monero_rpc::DaemonJsonRpcClient,
     monero_wallet: Mutex<monero_rpc::WalletClient>,
     bch_min_conf: u32,
+    bch_mempool: bool,
     monero_network: monero::Network,
     bch_network: Network,
+    xmr_recv: Option<monero::Address>,
+    bch_wallet: Option<BchWallet>,
+    xmr_wallet: Option<XmrWallet>,
+    pricing: Pricing,
 
     timelock1: u32,
     timelock2: u32,
+    min_mining_fee: u64,
+    max_mining_fee: u64,
+}
+
+impl AppState {
+    fn lifecycle(&self) -> Lifecycle<'_> {
+        Lifecycle {
+            store: self.store.as_ref(),
+            bch: &self.bch_server,
+            watcher: &self.watcher,
+        }
+    }
 }
 
 type TAppState = Arc<AppState>;
 
 async fn check_xmr_wallets(state: &TAppState) {
-    let base_path = "./.trades/ongoing/";
-    let mut entries = fs::read_dir(base_path).await.unwrap();
-    while let Some(entry) = entries.next_entry().await.unwrap() {
-        if !entry.path().is_file() {
-            continue;
-        }
-        let filename = entry.file_name().into_string().unwrap();
-        if !filename.ends_with("-server.json") {
-            continue;
+    let trades = match state.store.list().await {
+        Ok(trades) => trades,
+        Err(e) => {
+            println!("Cannot list trades: {e:?}");
+            return;
         }
+    };
 
-        let trade_id = filename.split("-").next().unwrap().to_string();
-        let mut trade = TradePersist::restore(get_file_path(&trade_id))
-            .await
-            .unwrap();
+    for info in trades {
+        let trade_id = info.trade_id;
+        let Ok(mut trade) = state.store.load(&trade_id).await else {
+            continue;
+        };
         match trade.config.swap {
             SwapWrapper::Bob(inner) => {
                 let mut runner = bob::Runner {
                     inner,
-                    trade_id,
+                    trade_id: trade_id.clone(),
                     bch: &state.bch_server,
                     monero_wallet: &state.monero_wallet,
                     monerod: &state.monerod,
+                    bch_wallet: state.bch_wallet.as_ref(),
                     min_bch_conf: state.bch_min_conf,
+                    bch_recv_key: trade.config.refund_private_key,
                 };
                 let _ = runner.check_xmr().await;
                 trade.config.swap = SwapWrapper::Bob(runner.inner);
             }
-            _ => {}
+            SwapWrapper::Alice(inner) => {
+                let mut runner = alice::Runner {
+                    inner,
+                    trade_id: trade_id.clone(),
+                    bch: &state.bch_server,
+                    monero_wallet: &state.monero_wallet,
+                    monerod: &state.monerod,
+                    min_bch_conf: state.bch_min_conf,
+                    bch_recv_key: trade.config.refund_private_key,
+                    xmr_wallet: state.xmr_wallet.as_ref(),
+                };
+                let _ = runner.check_xmr().await;
+                trade.config.swap = SwapWrapper::Alice(runner.inner);
+            }
+        }
+        if let Err(e) = state.lifecycle().save(trade).await {
+            println!("[{trade_id}]: Cannot save trade: {e:?}");
         }
-        trade.save().await;
     }
 }
 
-async fn check_bch_wallets(state: &TAppState) {
-    let base_path = "./.trades/ongoing/";
-    let mut entries = fs::read_dir(base_path).await.unwrap();
-    while let Some(entry) = entries.next_entry().await.unwrap() {
-        if !entry.path().is_file() {
-            continue;
+/// Subscribe to the contracts of the trades created before a restart
+async fn watch_bch_wallets(state: &TAppState) {
+    let trades = match state.store.list().await {
+        Ok(trades) => trades,
+        Err(e) => {
+            println!("Cannot list trades: {e:?}");
+            return;
         }
-        let filename = entry.file_name().into_string().unwrap();
-        if !filename.ends_with("-server.json") {
+    };
+
+    for info in trades {
+        let Ok(trade) = state.store.load(&info.trade_id).await else {
             continue;
+        };
+        watch_trade(state, &info.trade_id, &trade.config.swap).await;
+    }
+}
+
+pub async fn watch_trade(state: &TAppState, trade_id: &str, swap: &SwapWrapper) {
+    let contract_pair = match swap {
+        SwapWrapper::Alice(alice) => alice.get_contract_pair(),
+        SwapWrapper::Bob(bob) => bob.get_contract_pair(),
+    };
+
+    if let Some(contract_pair) = contract_pair {
+        let watch = state
+            .watcher
+            .watch(&state.bch_server, trade_id, &contract_pair)
+            .await;
+        if let Err(e) = watch {
+            println!("[{trade_id}]: Cannot watch contracts: {e}");
         }
+    }
+}
 
-        let trade_id = filename.split("-").next().unwrap().to_string();
-        let mut trade = TradePersist::restore(get_file_path(&trade_id))
-            .await
-            .unwrap();
+async fn check_bch_trade(state: &TAppState, trade_id: String) {
+    let txs = state.watcher.confirmed_txs(&trade_id, state.bch_min_conf);
+    let mempool_txs = match state.bch_mempool {
+        true => state.watcher.mempool_txs(&trade_id),
+        false => Vec::new(),
+    };
+    let Ok(mut trade) = state.store.load(&trade_id).await else {
+        return;
+    };
 
-        match trade.config.swap {
-            SwapWrapper::Bob(bob) => {
-                let mut runner = bob::Runner {
-                    trade_id,
-                    inner: bob,
-                    bch: &state.bch_server,
-                    min_bch_conf: state.bch_min_conf,
-                    monerod: &state.monerod,
-                    monero_wallet: &state.monero_wallet,
-                };
-                let _ = runner.check_bch().await;
-                trade.config.swap = SwapWrapper::Bob(runner.inner);
-            }
-            SwapWrapper::Alice(alice) => {
-                let mut runner = alice::Runner {
-                    inner: alice,
-                    bch: &state.bch_server,
-                    min_bch_conf: state.bch_min_conf,
-                };
-                let _ = runner.check_bch().await;
-                trade.config.swap = SwapWrapper::Alice(runner.inner);
-            }
+    match trade.config.swap {
+        SwapWrapper::Bob(bob) => {
+            let mut runner = bob::Runner {
+                trade_id: trade_id.clone(),
+                inner: bob,
+                bch: &state.bch_server,
+                bch_wallet: state.bch_wallet.as_ref(),
+                min_bch_conf: state.bch_min_conf,
+                bch_recv_key: trade.config.refund_private_key,
+                monerod: &state.monerod,
+                monero_wallet: &state.monero_wallet,
+            };
+            runner.process_bch_txs(txs).await;
+            runner.process_bch_mempool_txs(mempool_txs).await;
+            trade.config.swap = SwapWrapper::Bob(runner.inner);
+        }
+        SwapWrapper::Alice(alice) => {
+            let mut runner = alice::Runner {
+                trade_id: trade_id.clone(),
+                inner: alice,
+                bch: &state.bch_server,
+                min_bch_conf: state.bch_min_conf,
+                bch_recv_key: trade.config.refund_private_key,
+                xmr_wallet: state.xmr_wallet.as_ref(),
+                monerod: &state.monerod,
+                monero_wallet: &state.monero_wallet,
+            };
+            runner.process_bch_txs(txs).await;
+            runner.process_bch_mempool_txs(mempool_txs).await;
+            trade.config.swap = SwapWrapper::Alice(runner.inner);
         }
-        trade.save().await;
+    }
+    if let Err(e) = state.lifecycle().save(trade).await {
+        println!("[{trade_id}]: Cannot save trade: {e:?}");
     }
 }
 
 #[tokio::main]
 async fn main() {
-    let bch_min_conf = 1;
+    let config = config::load().unwrap();
+    let node = config.node;
+    if let Some(passphrase) = &node.trade_passphrase {
+        persist::set_passphrase(passphrase);
+    }
+    let electrum_servers = node.electrum_servers().unwrap();
+
+    let store: Box<dyn SwapStore> = match config.trade_store.strip_prefix("sqlite:") {
+        Some(path) => Box::new(SqliteStore::open(path).unwrap()),
+        None => Box::new(FileStore::new(&config.trade_store, "-server.json")),
+    };
 
-    let monerod_addr = "http://localhost:18081";
-    let monero_wallet_addr = "http://localhost:8081";
-    let fullcrum_tcp = "localhost:50001";
+    // Bob trades fund the swaplock from this wallet
+    let bch_wallet = node.bch_wallet_wif.map(|wif| {
+        BchWallet::new(
+            bitcoincash::PrivateKey::from_wif(&wif).unwrap(),
+            node.bch_network,
+        )
+    });
 
-    let monero_network = monero::Network::Mainnet;
-    let bch_network = Network::Regtest;
+    // Alice trades lock the xmr from this monero-wallet-rpc wallet
+    let xmr_wallet = node
+        .xmr_wallet
+        .map(|filename| XmrWallet::new(filename, node.xmr_wallet_password));
 
-    let timelock1 = 2;
-    let timelock2 = 2;
+    let pricing = Pricing::new(
+        Box::new(FilePriceSource::new(config.price_file)),
+        config.spread,
+        bitcoincash::Amount::from_sat(config.min_bch_sats),
+        bitcoincash::Amount::from_sat(config.max_bch_sats),
+        Duration::from_secs(config.quote_ttl_secs),
+    );
 
     // ===================================================
 
     let monerod = monero_rpc::RpcClientBuilder::new()
-        .build(monerod_addr)
+        .build(node.monerod_addr)
         .unwrap()
         .daemon();
     let monero_wallet = Mutex::new(
         monero_rpc::RpcClientBuilder::new()
-            .build(monero_wallet_addr)
+            .build(node.monero_wallet_addr)
             .unwrap()
             .wallet(),
     );
 
-    let socket = TcpStream::connect(fullcrum_tcp).await.unwrap();
-    let bch_server = blockchain::TcpElectrum::new(socket);
+    let bch_server = blockchain::TcpElectrum::connect(
+        electrum_servers,
+        Duration::from_secs(node.electrum_timeout_secs),
+    )
+    .await
+    .unwrap();
 
     let state = Arc::new(AppState {
+        store,
         bch_server: bch_server.clone(),
+        watcher: ScriptWatcher::new(node.bch_network),
         monerod,
         monero_wallet,
-        bch_min_conf,
-        monero_network,
-        bch_network,
-        timelock1,
-        timelock2,
+        bch_min_conf: node.bch_min_conf,
+        bch_mempool: node.bch_mempool,
+        monero_network: node.monero_network,
+        bch_network: node.bch_network,
+        xmr_recv: node.xmr_recv,
+        bch_wallet,
+        xmr_wallet,
+        pricing,
+        timelock1: node.timelock1,
+        timelock2: node.timelock2,
+        min_mining_fee: node.min_mining_fee,
+        max_mining_fee: node.max_mining_fee,
     });
 
     tokio::spawn({
@@ -167,31 +279,42 @@ async fn main() {
     tokio::spawn({
         let state = state.clone();
         let mut receiver = state.bch_server.subscribe();
-        let _ = state
-            .bch_server
-            .send("blockchain.headers.subscribe", json!([]))
+        let tip = state.bch_server.headers_subscribe().await.unwrap();
+        state
+            .watcher
+            .sync_tip(&state.bch_server, &tip)
             .await
             .unwrap();
+        watch_bch_wallets(&state).await;
 
         async move {
             loop {
-                let data = receiver.recv().await.unwrap();
-                let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+                let trade_ids = match receiver.recv().await {
+                    Ok(data) => {
+                        state
+                            .watcher
+                            .on_notification(&state.bch_server, &data)
+                            .await
+                    }
+                    Err(RecvError::Lagged(_)) => state.watcher.refresh(&state.bch_server).await,
+                    Err(RecvError::Closed) => break,
+                };
 
-                if data["method"].as_str().unwrap() != "blockchain.headers.subscribe" {
-                    continue;
+                match trade_ids {
+                    Ok(trade_ids) => {
+                        for trade_id in trade_ids {
+                            check_bch_trade(&state, trade_id).await;
+                        }
+                    }
+                    Err(e) => println!("Cannot process notification: {e}"),
                 }
-
-                println!("New block found. Rescanning addresses");
-                check_bch_wallets(&state).await
             }
         }
     });
 
     let app = Router::new().nest("/trader", trader::trader(state));
 
-    let port = env::var("PORT").unwrap_or("8080".to_owned());
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}"))
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port))
         .await
         .unwrap();
 
@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use protocol::{bitcoincash, monero};
+use serde::Serialize;
+
+use crate::utils::random_str;
+
+// 1 sat = 1e-8 bch, 1 pico = 1e-12 xmr
+const PICO_PER_SAT: f64 = 10_000.0;
+
+pub trait PriceSource: Send + Sync {
+    /// Amount of xmr for 1 bch
+    fn xmr_per_bch(&self) -> anyhow::Result<f64>;
+}
+
+/// Reads the price from a file containing a single number.
+/// The file is read on every quote so it can be updated while running
+pub struct FilePriceSource {
+    path: PathBuf,
+}
+
+impl FilePriceSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FilePriceSource { path: path.into() }
+    }
+}
+
+impl PriceSource for FilePriceSource {
+    fn xmr_per_bch(&self) -> anyhow::Result<f64> {
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Cannot read price file {}", self.path.display()))?;
+        let price = content.trim().parse::<f64>()?;
+        if !price.is_finite() || price <= 0.0 {
+            bail!("Invalid price {price}");
+        }
+
+        Ok(price)
+    }
+}
+
+#[cfg(test)]
+pub struct MockPriceSource(pub f64);
+
+#[cfg(test)]
+impl PriceSource for MockPriceSource {
+    fn xmr_per_bch(&self) -> anyhow::Result<f64> {
+        Ok(self.0)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidPath,
+    AmountOutOfRange,
+    PriceUnavailable(String),
+    QuoteNotFound,
+    QuoteExpired,
+    QuoteMismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Quote {
+    pub id: String,
+    pub path: String,
+    #[serde(with = "bitcoincash::util::amount::serde::as_sat")]
+    pub bch_amount: bitcoincash::Amount,
+    #[serde(with = "monero::util::amount::serde::as_pico")]
+    pub xmr_amount: monero::Amount,
+    /// Unix timestamp in seconds
+    pub expires_at: u64,
+}
+
+pub struct Pricing {
+    source: Box<dyn PriceSource>,
+    /// Fraction kept by the server, 0.01 = 1%
+    pub spread: f64,
+    pub min_bch: bitcoincash::Amount,
+    pub max_bch: bitcoincash::Amount,
+    pub quote_ttl: Duration,
+
+    quotes: Mutex<HashMap<String, Quote>>,
+}
+
+impl Pricing {
+    pub fn new(
+        source: Box<dyn PriceSource>,
+        spread: f64,
+        min_bch: bitcoincash::Amount,
+        max_bch: bitcoincash::Amount,
+        quote_ttl: Duration,
+    ) -> Self {
+        Pricing {
+            source,
+            spread,
+            min_bch,
+            max_bch,
+            quote_ttl,
+            quotes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `path` is from the client side, same as on trade creation
+    pub fn quote(&self, path: &str, bch_amount: bitcoincash::Amount) -> Result<Quote, Error> {
+        if bch_amount < self.min_bch || bch_amount > self.max_bch {
+            return Err(Error::AmountOutOfRange);
+        }
+
+        let price = self
+            .source
+            .xmr_per_bch()
+            .map_err(|e| Error::PriceUnavailable(e.to_string()))?;
+
+        let pico = bch_amount.to_sat() as f64 * PICO_PER_SAT;
+        let xmr_pico = match path {
+            // we send the xmr
+            "bch->xmr" => (pico * price * (1.0 - self.spread)).floor(),
+            // we receive the xmr
+            "xmr->bch" => (pico * price * (1.0 + self.spread)).ceil(),
+            _ => return Err(Error::InvalidPath),
+        };
+
+        let now = unix_now();
+        let quote = Quote {
+            id: random_str(10),
+            path: path.to_owned(),
+            bch_amount,
+            xmr_amount: monero::Amount::from_pico(xmr_pico as u64),
+            expires_at: now + self.quote_ttl.as_secs(),
+        };
+
+        let mut quotes = self.quotes.lock().unwrap();
+        quotes.retain(|_, quote| quote.expires_at > now);
+        quotes.insert(quote.id.clone(), quote.clone());
+
+        Ok(quote)
+    }
+
+    /// Consume the quote if it is still valid for the requested trade
+    pub fn take_quote(
+        &self,
+        id: &str,
+        path: &str,
+        bch_amount: bitcoincash::Amount,
+        xmr_amount: monero::Amount,
+    ) -> Result<Quote, Error> {
+        let mut quotes = self.quotes.lock().unwrap();
+        let quote = quotes.get(id).ok_or(Error::QuoteNotFound)?;
+
+        if quote.expires_at <= unix_now() {
+            quotes.remove(id);
+            return Err(Error::QuoteExpired);
+        }
+
+        if quote.path != path || quote.bch_amount != bch_amount || quote.xmr_amount != xmr_amount {
+            return Err(Error::QuoteMismatch);
+        }
+
+        Ok(quotes.remove(id).unwrap())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use protocol::{bitcoincash::Amount, monero};
+
+    use super::{Error, MockPriceSource, Pricing};
+
+    fn pricing(quote_ttl: Duration) -> Pricing {
+        Pricing::new(
+            Box::new(MockPriceSource(0.5)),
+            0.01,
+            Amount::from_sat(10_000),
+            Amount::from_sat(1_000_000),
+            quote_ttl,
+        )
+    }
+
+    #[test]
+    fn should_apply_spread_in_server_favor() {
+        let pricing = pricing(Duration::from_secs(60));
+        let bch_amount = Amount::from_sat(100_000);
+
+        // 0.001 bch * 0.5 = 0.0005 xmr
+        let quote = pricing.quote("bch->xmr", bch_amount).unwrap();
+        assert_eq!(quote.xmr_amount, monero::Amount::from_pico(495_000_000));
+
+        let quote = pricing.quote("xmr->bch", bch_amount).unwrap();
+        assert_eq!(quote.xmr_amount, monero::Amount::from_pico(505_000_000));
+    }
+
+    #[test]
+    fn should_reject_out_of_range_amount() {
+        let pricing = pricing(Duration::from_secs(60));
+
+        let quote = pricing.quote("bch->xmr", Amount::from_sat(9_999));
+        assert!(matches!(quote, Err(Error::AmountOutOfRange)));
+
+        let quote = pricing.quote("bch->xmr", Amount::from_sat(1_000_001));
+        assert!(matches!(quote, Err(Error::AmountOutOfRange)));
+    }
+
+    #[test]
+    fn should_take_quote_once() {
+        let pricing = pricing(Duration::from_secs(60));
+        let quote = pricing
+            .quote("bch->xmr", Amount::from_sat(100_000))
+            .unwrap();
+
+        let taken = pricing.take_quote(&quote.id, "xmr->bch", quote.bch_amount, quote.xmr_amount);
+        assert!(matches!(taken, Err(Error::QuoteMismatch)));
+
+        let taken = pricing.take_quote(&quote.id, "bch->xmr", quote.bch_amount, quote.xmr_amount);
+        assert!(taken.is_ok());
+
+        let taken = pricing.take_quote(&quote.id, "bch->xmr", quote.bch_amount, quote.xmr_amount);
+        assert!(matches!(taken, Err(Error::QuoteNotFound)));
+    }
+
+    #[test]
+    fn should_reject_expired_quote() {
+        let pricing = pricing(Duration::ZERO);
+        let quote = pricing
+            .quote("bch->xmr", Amount::from_sat(100_000))
+            .unwrap();
+
+        let taken = pricing.take_quote(&quote.id, "bch->xmr", quote.bch_amount, quote.xmr_amount);
+        assert!(matches!(taken, Err(Error::QuoteExpired)));
+    }
+}
@@ -1,36 +1,79 @@
-use std::{fs, io::Write, net::SocketAddr};
+use std::net::SocketAddr;
 
 use axum::{
-    extract::{ConnectInfo, Path, State},
+    extract::{ConnectInfo, Path, Query, State},
     http::StatusCode,
-    routing::{patch, post},
+    routing::{get, patch, post},
     Json, Router,
 };
 use protocol::{
-    bitcoincash,
+    alice::{self, Alice},
+    bitcoincash, blockchain,
     bob::{self, Bob},
     keys::{bitcoin::random_private_key, KeyPrivate},
     monero,
-    persist::{Config, Error as PersistError, TradePersist},
-    protocol::{Swap, SwapEvents, SwapWrapper, Transition},
+    persist::{Config, Error as PersistError, VERSION},
+    protocol::{Swap, SwapEvents, SwapWrapper, Transition, DEFAULT_MINING_FEE},
 };
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    pricing::{Error as PricingError, Quote},
     utils::{random_str, ApiResult, Error, JsonRej},
-    TAppState,
+    watch_trade, TAppState,
 };
 
 pub fn trader(state: TAppState) -> Router {
     Router::new()
         .route("/", post(create))
+        .route("/quote", get(quote))
         .route("/:trade_id", patch(transition).get(get_transition))
         .with_state(state)
 }
 
-#[inline]
-pub fn get_file_path(trade_id: &str) -> String {
-    format!("./.trades/ongoing/{trade_id}-server.json")
+fn persist_error(error: PersistError) -> Error {
+    match error {
+        PersistError::NotFound => Error::new(StatusCode::NOT_FOUND, "Trade id not found"),
+        PersistError::Unknown(e) => Error::from(e),
+    }
+}
+
+// ==========================================
+// SECTION: Quote
+// ==========================================
+
+#[derive(Deserialize)]
+struct QuoteRequest {
+    path: String,
+    #[serde(with = "bitcoincash::util::amount::serde::as_sat")]
+    bch_amount: bitcoincash::Amount,
+}
+
+fn pricing_error(error: PricingError) -> Error {
+    match error {
+        PricingError::InvalidPath => Error::new(StatusCode::NOT_IMPLEMENTED, "Pair not available"),
+        PricingError::AmountOutOfRange => Error::new(StatusCode::FORBIDDEN, "Invalid amount"),
+        PricingError::PriceUnavailable(e) => {
+            eprintln!("Price unavailable: {e}");
+            Error::new(StatusCode::SERVICE_UNAVAILABLE, "Price unavailable")
+        }
+        PricingError::QuoteNotFound => Error::new(StatusCode::FORBIDDEN, "Quote not found"),
+        PricingError::QuoteExpired => Error::new(StatusCode::FORBIDDEN, "Quote expired"),
+        PricingError::QuoteMismatch => {
+            Error::new(StatusCode::FORBIDDEN, "Trade does not match the quote")
+        }
+    }
+}
+
+async fn quote(
+    State(state): State<TAppState>,
+    Query(request): Query<QuoteRequest>,
+) -> ApiResult<Json<Quote>> {
+    let quote = state
+        .pricing
+        .quote(&request.path, request.bch_amount)
+        .map_err(pricing_error)?;
+    Ok(Json(quote))
 }
 
 // ==========================================
@@ -46,6 +89,7 @@ struct CreateRequest {
     xmr_amount: monero::Amount,
     timelock1: u32,
     timelock2: u32,
+    quote_id: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -58,14 +102,20 @@ async fn create(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     JsonRej(request): JsonRej<CreateRequest>,
 ) -> ApiResult<Json<CreateResponse>> {
-    if request.bch_amount.to_sat() != 100000 || request.xmr_amount.as_pico() != 100000 {
-        return Err(Error::new(StatusCode::FORBIDDEN, "Invalid amount"));
-    }
-
     if request.timelock1 != state.timelock1 || request.timelock2 != state.timelock2 {
         return Err(Error::new(StatusCode::FORBIDDEN, "Invalid timelock"));
     }
 
+    state
+        .pricing
+        .take_quote(
+            &request.quote_id,
+            &request.path,
+            request.bch_amount,
+            request.xmr_amount,
+        )
+        .map_err(pricing_error)?;
+
     let trade_id = random_str(10);
 
     let (refund_priv, refund_script) = {
@@ -76,6 +126,12 @@ async fn create(
         (refund_priv, script)
     };
 
+    // Propose what the network currently asks, within our limits
+    let mining_fee = blockchain::estimate_mining_fee(&state.bch_server, 1)
+        .await
+        .unwrap_or(DEFAULT_MINING_FEE)
+        .clamp(state.min_mining_fee, state.max_mining_fee);
+
     let swap = Swap {
         id: trade_id.clone(),
         keys: KeyPrivate::random(state.bch_network),
@@ -84,16 +140,16 @@ async fn create(
         xmr_network: state.monero_network,
         bch_network: state.bch_network,
         bch_recv: refund_script,
+        xmr_recv: state.xmr_recv,
         timelock1: request.timelock1,
         timelock2: request.timelock2,
+        mining_fee,
+        min_mining_fee: state.min_mining_fee,
+        max_mining_fee: state.max_mining_fee,
     };
 
     let swap = match request.path.as_str() {
-        // TODO:
-        // "bch->xmr" => SwapWrapper::Alice(Alice {
-        //     state: alice::State::Init,
-        //     swap,
-        // }),
+        "bch->xmr" => SwapWrapper::Alice(Alice::new(swap)),
         "xmr->bch" => SwapWrapper::Bob(Bob::new(swap)),
         _ => {
             return Err(Error::new(
@@ -103,16 +159,19 @@ async fn create(
         }
     };
 
-    let serialized = serde_json::to_vec_pretty(&Config {
-        swap,
-        refund_private_key: refund_priv,
-    })?;
-
-    fs::OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(get_file_path(&trade_id))?
-        .write(&serialized)?;
+    state
+        .store
+        .create(
+            &trade_id,
+            Config {
+                version: VERSION,
+                swap,
+                refund_private_key: refund_priv,
+                completion: None,
+            },
+        )
+        .await
+        .map_err(persist_error)?;
 
     println!("[INFO] New Trade: {trade_id}");
     println!("       Client IP: {addr}");
@@ -137,33 +196,46 @@ async fn transition(
     // ! we always open the file even on private transition
     // ! we can put a matcher here to reduce file opening
 
-    let mut trade = match TradePersist::restore(get_file_path(&trade_id)).await {
-        Ok(v) => v,
-        Err(e) => match e {
-            PersistError::NotFound => {
-                return Err(Error::new(StatusCode::NOT_FOUND, "Trade id not found"))
-            }
-            PersistError::Unknown(e) => return Err(Error::from(e.to_string())),
-        },
-    };
+    let mut trade = state.store.load(&trade_id).await.map_err(persist_error)?;
 
-    match trade.config.swap {
+    let result = match trade.config.swap {
         SwapWrapper::Bob(inner) => {
             let mut bob = bob::Runner {
                 inner,
-                trade_id,
+                trade_id: trade_id.clone(),
                 bch: &state.bch_server,
                 monero_wallet: &state.monero_wallet,
                 monerod: &state.monerod,
+                bch_wallet: state.bch_wallet.as_ref(),
                 min_bch_conf: state.bch_min_conf,
+                bch_recv_key: trade.config.refund_private_key,
             };
-            bob.pub_transition(request).await?;
-
+            let result = bob.pub_transition(request).await;
             trade.config.swap = SwapWrapper::Bob(bob.inner);
-            trade.save().await;
+            result
         }
-        SwapWrapper::Alice(_) => {}
-    }
+        SwapWrapper::Alice(inner) => {
+            let mut alice = alice::Runner {
+                inner,
+                trade_id: trade_id.clone(),
+                bch: &state.bch_server,
+                monero_wallet: &state.monero_wallet,
+                monerod: &state.monerod,
+                min_bch_conf: state.bch_min_conf,
+                bch_recv_key: trade.config.refund_private_key,
+                xmr_wallet: state.xmr_wallet.as_ref(),
+            };
+            let result = alice.pub_transition(request).await;
+            trade.config.swap = SwapWrapper::Alice(alice.inner);
+            result
+        }
+    };
+
+    // the contracts exist once the Msg0 are exchanged
+    watch_trade(&state, &trade_id, &trade.config.swap).await;
+    // even on error, a rejected trade is archived
+    state.lifecycle().save(trade).await.map_err(persist_error)?;
+    result?;
 
     Ok(Json(TransitionResponse { error: false }))
 }
@@ -172,17 +244,14 @@ async fn transition(
 // SECTION: Get Transition
 // ==========================================
 
-async fn get_transition(Path(trade_id): Path<String>) -> ApiResult<Json<Option<Transition>>> {
-    match TradePersist::restore(get_file_path(&trade_id)).await {
-        Ok(value) => match value.config.swap {
-            SwapWrapper::Alice(alice) => Ok(Json(alice.get_transition())),
-            SwapWrapper::Bob(bob) => Ok(Json(bob.get_transition())),
-        },
-        Err(e) => match e {
-            PersistError::NotFound => {
-                return Err(Error::new(StatusCode::NOT_FOUND, "Trade id not found"))
-            }
-            PersistError::Unknown(e) => return Err(Error::from(e.to_string())),
-        },
+async fn get_transition(
+    State(state): State<TAppState>,
+    Path(trade_id): Path<String>,
+) -> ApiResult<Json<Option<Transition>>> {
+    let trade = state.store.load(&trade_id).await.map_err(persist_error)?;
+
+    match trade.config.swap {
+        SwapWrapper::Alice(alice) => Ok(Json(alice.get_transition())),
+        SwapWrapper::Bob(bob) => Ok(Json(bob.get_transition())),
     }
 }